@@ -1,7 +1,22 @@
+use crate::error::{Error, Result};
+use futures::StreamExt;
+use retry::RetryPolicy;
 use serde::{Deserialize, Serialize};
-use std::env;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::time::sleep_until;
 
+pub mod cassette;
+pub mod functions;
+pub mod json;
+pub mod metering;
+#[cfg(test)]
+pub mod mock;
 pub mod request;
+pub mod retry;
+pub mod stream;
+pub mod tokens;
 
 #[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
 #[serde(rename_all = "lowercase")]
@@ -9,6 +24,9 @@ pub enum Role {
     User,
     Assistant,
     System,
+    Tool,
+    // Legacy equivalent of tool, for older models.
+    Function,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
@@ -18,8 +36,14 @@ pub struct Message {
     pub content: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub name: Option<String>,
+    // Legacy equivalent of tool_calls, for older models.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub function_call: Option<FunctionCall>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+    // The call a tool message answers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
@@ -28,67 +52,283 @@ pub struct FunctionCall {
     pub arguments: String,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type", default)]
+    pub kind: ToolKind,
+    pub function: FunctionCall,
+}
+
+// Functions are the only kind of tools the API offers for now.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ToolKind {
+    #[default]
+    Function,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct ApiResponse {
     pub choices: Vec<Choice>,
     pub usage: Usage,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Choice {
     pub message: Message,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
 pub struct Usage {
+    #[serde(default)]
+    pub prompt_tokens: u32,
+    #[serde(default)]
+    pub completion_tokens: u32,
     pub total_tokens: u32,
 }
 
+// Anything able to answer a chat completion request. The narrator only talks to the model
+// through this trait so providers, test doubles and middleware can be swapped in.
+pub trait ChatBackend: Clone + Send + Sync + 'static {
+    fn submit(&self, body: request::Body) -> impl Future<Output = Result<ApiResponse>> + Send;
+
+    // Same as submit, calling on_delta as parts of the response arrive. Backends unable to
+    // stream deliver the whole message as a single delta.
+    fn stream(
+        &self,
+        body: request::Body,
+        mut on_delta: impl FnMut(&stream::Delta) + Send,
+    ) -> impl Future<Output = Result<ApiResponse>> + Send {
+        async move {
+            let api_response = self.submit(body).await?;
+            on_delta(&stream::Delta::from_message(&api_response.message()?));
+            Ok(api_response)
+        }
+    }
+}
+
+// Transient failures carry the delay the server asked for, if any.
+enum Failure {
+    Fatal(Error),
+    Transient(Error, Option<Duration>),
+}
+
 #[derive(Clone)]
 pub struct Service {
     client: reqwest::Client,
-    api_key: String,
+    endpoint: String,
+    // Local servers usually do not need one.
+    api_key: Option<String>,
+    retry_policy: RetryPolicy,
+    // Shared by every clone so that concurrent requests all back off when a rate limit is hit.
+    paused_until: Arc<Mutex<Option<Instant>>>,
 }
 
 impl Service {
-    pub fn new() -> Self {
-        let api_key = env::var("OPENAI_API_KEY").expect("OPENAI_API_KEY not set");
+    pub fn new(endpoint: String, api_key: Option<String>, retry_policy: RetryPolicy) -> Self {
         Self {
             client: reqwest::Client::new(),
+            endpoint,
             api_key,
+            retry_policy,
+            paused_until: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    async fn request(&self, body: &request::Body) -> reqwest::Result<reqwest::Response> {
+        let json = serde_json::to_string_pretty(body).unwrap();
+        eprintln!("SENDING {}", &json);
+
+        let mut request = self.client.post(&self.endpoint).json(body);
+        if let Some(api_key) = &self.api_key {
+            request = request.bearer_auth(api_key);
         }
+
+        request.send().await
     }
 
-    pub async fn submit(&self, body: request::Body) -> ApiResponse {
-        let response = self.request(body).await.unwrap();
+    async fn attempt(
+        &self,
+        body: &request::Body,
+    ) -> std::result::Result<reqwest::Response, Failure> {
+        let response = match self.request(body).await {
+            Ok(response) => response,
+            Err(error) => return Err(Failure::Transient(Error::Transport(error), None)),
+        };
         let status = response.status();
-        let text = response.text().await.unwrap();
+        let headers = response.headers().clone();
+
+        if let Some(delay) = retry::rate_limit_reset(&headers) {
+            self.pause(self.retry_policy.bound(delay));
+        }
+
+        if !status.is_client_error() && !status.is_server_error() {
+            return Ok(response);
+        }
+
+        let text = match response.text().await {
+            Ok(text) => text,
+            Err(error) => return Err(Failure::Transient(Error::Transport(error), None)),
+        };
+        let error = Error::Http { status, body: text };
 
-        if status.is_client_error() || status.is_server_error() {
-            panic!("Error: {}", text);
+        match retry::is_transient(status) {
+            true => Err(Failure::Transient(error, retry::retry_after(&headers))),
+            false => Err(Failure::Fatal(error)),
         }
+    }
+
+    // Sends the request until a successful response is read or the retry policy gives up. A
+    // response failing to be read counts as a failed attempt.
+    async fn send<T, F>(
+        &self,
+        body: &request::Body,
+        read: impl Fn(reqwest::Response) -> F,
+    ) -> Result<T>
+    where
+        F: Future<Output = reqwest::Result<T>>,
+    {
+        tokens::check(body)?;
+        let mut attempts = 0;
+
+        loop {
+            attempts += 1;
+            self.wait_for_rate_limit().await;
+
+            let (error, delay) = match self.attempt(body).await {
+                Ok(response) => match read(response).await {
+                    Ok(value) => return Ok(value),
+                    Err(error) => (Error::Transport(error), None),
+                },
+                Err(Failure::Fatal(error)) => return Err(error),
+                Err(Failure::Transient(error, delay)) => (error, delay),
+            };
+
+            if attempts >= self.retry_policy.max_attempts {
+                return Err(Error::ExhaustedRetries {
+                    attempts,
+                    last: Box::new(error),
+                });
+            }
+
+            let delay = match delay {
+                Some(delay) => self.retry_policy.bound(delay),
+                None => self.retry_policy.backoff(attempts),
+            };
+            eprintln!("Retrying in {:?}: {}", delay, error);
+            self.pause(delay);
+        }
+    }
+
+    fn pause(&self, delay: Duration) {
+        let until = Instant::now() + delay;
+        let mut paused_until = self.paused_until.lock().unwrap();
+
+        if paused_until.is_none_or(|current| current < until) {
+            *paused_until = Some(until);
+        }
+    }
+
+    // Other requests may push the pause further while this one is waiting.
+    async fn wait_for_rate_limit(&self) {
+        loop {
+            let paused_until = *self.paused_until.lock().unwrap();
+
+            match paused_until {
+                Some(until) if until > Instant::now() => sleep_until(until.into()).await,
+                _ => return,
+            }
+        }
+    }
+}
+
+impl ChatBackend for Service {
+    async fn submit(&self, body: request::Body) -> Result<ApiResponse> {
+        let text = self.send(&body, reqwest::Response::text).await?;
+        let api_response: ApiResponse = serde_json::from_str(&text)?;
 
-        let api_response: ApiResponse = serde_json::from_str(&text).unwrap();
         eprintln!("RECEIVED {:#?}", api_response);
-        api_response
+        Ok(api_response)
     }
 
-    async fn request(&self, body: request::Body) -> reqwest::Result<reqwest::Response> {
-        let json = serde_json::to_string_pretty(&body).unwrap();
-        eprintln!("SENDING {}", &json);
+    async fn stream(
+        &self,
+        mut body: request::Body,
+        mut on_delta: impl FnMut(&stream::Delta) + Send,
+    ) -> Result<ApiResponse> {
+        body.stream = Some(true);
+        body.stream_options = Some(request::StreamOptions {
+            include_usage: true,
+        });
+
+        // Deltas are reported as they arrive, a broken stream cannot be retried
+        let response = self.send(&body, |response| async { Ok(response) }).await?;
+        let mut bytes = response.bytes_stream();
+        let mut decoder = stream::EventDecoder::default();
+        let mut accumulator = stream::Accumulator::default();
+
+        while let Some(read) = bytes.next().await {
+            for chunk in decoder.push(&read?)? {
+                if let Some(choice) = chunk.choices.first() {
+                    on_delta(&choice.delta);
+                }
+                accumulator.push(&chunk);
+            }
+        }
+
+        let api_response = accumulator.finish();
+        eprintln!("RECEIVED {:#?}", api_response);
+        Ok(api_response)
+    }
+}
+
+impl Message {
+    pub fn new(role: Role, content: Option<String>) -> Self {
+        Self {
+            role,
+            content,
+            name: None,
+            function_call: None,
+            tool_calls: None,
+            tool_call_id: None,
+        }
+    }
+
+    // The answer to a tool call, such as the result of the function.
+    pub fn tool_result(tool_call_id: &str, content: String) -> Self {
+        Self {
+            tool_call_id: Some(tool_call_id.to_string()),
+            ..Self::new(Role::Tool, Some(content))
+        }
+    }
 
-        self.client
-            .post("https://api.openai.com/v1/chat/completions")
-            .bearer_auth(&self.api_key)
-            .json(&body)
-            .send()
-            .await
+    // The call of the given function among the tool calls, if any.
+    pub fn tool_call(&self, name: &str) -> Option<&FunctionCall> {
+        self.tool_calls
+            .iter()
+            .flatten()
+            .map(|tool_call| &tool_call.function)
+            .find(|function| function.name == name)
+    }
+}
+
+impl ToolCall {
+    pub fn new(id: String, function: FunctionCall) -> Self {
+        Self {
+            id,
+            kind: ToolKind::Function,
+            function,
+        }
     }
 }
 
 impl ApiResponse {
-    pub fn message(&self) -> Message {
-        self.choices.get(0).unwrap().message.clone()
+    pub fn message(&self) -> Result<Message> {
+        match self.choices.first() {
+            Some(choice) => Ok(choice.message.clone()),
+            None => Err(Error::EmptyResponse),
+        }
     }
 }
 
@@ -97,12 +337,23 @@ mod tests {
     use super::*;
 
     pub fn user_message() -> Message {
-        Message {
-            role: Role::User,
-            content: Some(String::from("Hello, world!")),
-            function_call: None,
-            name: None,
-        }
+        Message::new(Role::User, Some(String::from("Hello, world!")))
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_a_pause_pushed_back() {
+        let service = Service::new(String::new(), None, RetryPolicy::default());
+        let start = Instant::now();
+        service.pause(Duration::from_millis(20));
+
+        let pushing = service.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            pushing.pause(Duration::from_millis(60));
+        });
+        service.wait_for_rate_limit().await;
+
+        assert!(start.elapsed() >= Duration::from_millis(65));
     }
 
     #[test]
@@ -125,4 +376,26 @@ mod tests {
 
         assert_eq!(json, r#"{"role":"user","content":"Hello, world!"}"#);
     }
+
+    #[test]
+    fn test_tool_messages_serialization() {
+        let json = r#"{
+            "role": "assistant",
+            "content": null,
+            "tool_calls": [{
+                "id": "call_1",
+                "type": "function",
+                "function": { "name": "chapter", "arguments": "{}" }
+            }]
+        }"#;
+        let message: Message = serde_json::from_str(json).unwrap();
+        assert_eq!(message.tool_call("chapter").unwrap().arguments, "{}");
+        assert!(message.tool_call("summary").is_none());
+
+        let answer = Message::tool_result("call_1", String::from("done"));
+        assert_eq!(
+            serde_json::to_string(&answer).unwrap(),
+            r#"{"role":"tool","content":"done","tool_call_id":"call_1"}"#
+        );
+    }
 }
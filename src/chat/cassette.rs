@@ -0,0 +1,213 @@
+use crate::chat::{request, stream, ApiResponse, ChatBackend};
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+// A cassette is a JSON lines file, one recorded exchange per line.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    key: String,
+    request: Value,
+    response: ApiResponse,
+}
+
+// Forwards requests to another backend and appends every exchange to a cassette.
+#[derive(Clone)]
+pub struct Recorder<B: ChatBackend> {
+    backend: B,
+    file: Arc<Mutex<File>>,
+}
+
+// Serves the responses of a cassette back, without any network access.
+#[derive(Clone)]
+pub struct Replayer {
+    responses: Arc<Mutex<HashMap<String, VecDeque<ApiResponse>>>>,
+}
+
+impl<B: ChatBackend> Recorder<B> {
+    pub fn new(backend: B, path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(Self {
+            backend,
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    fn record(&self, body: &request::Body, response: &ApiResponse) {
+        let entry = Entry {
+            key: key(body),
+            request: serde_json::to_value(body).unwrap(),
+            response: response.clone(),
+        };
+        let line = serde_json::to_string(&entry).unwrap();
+        let mut file = self.file.lock().unwrap();
+
+        if let Err(error) = writeln!(file, "{}", line) {
+            eprintln!("Unable to record request: {}", error);
+        }
+    }
+}
+
+impl<B: ChatBackend> ChatBackend for Recorder<B> {
+    async fn submit(&self, body: request::Body) -> Result<ApiResponse> {
+        let response = self.backend.submit(body.clone()).await?;
+        self.record(&body, &response);
+        Ok(response)
+    }
+
+    async fn stream(
+        &self,
+        body: request::Body,
+        on_delta: impl FnMut(&stream::Delta) + Send,
+    ) -> Result<ApiResponse> {
+        let response = self.backend.stream(body.clone(), on_delta).await?;
+        self.record(&body, &response);
+        Ok(response)
+    }
+}
+
+impl Replayer {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut responses: HashMap<String, VecDeque<ApiResponse>> = HashMap::new();
+
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let entry: Entry = serde_json::from_str(&line)?;
+            responses
+                .entry(entry.key)
+                .or_default()
+                .push_back(entry.response);
+        }
+
+        Ok(Self {
+            responses: Arc::new(Mutex::new(responses)),
+        })
+    }
+}
+
+impl ChatBackend for Replayer {
+    async fn submit(&self, body: request::Body) -> Result<ApiResponse> {
+        let key = key(&body);
+        let mut responses = self.responses.lock().unwrap();
+        let queue = match responses.get_mut(&key) {
+            Some(queue) => queue,
+            None => return Err(Error::NotRecorded(key)),
+        };
+
+        // Identical requests are answered in the order they were recorded, the last answer
+        // being repeated once the others have been consumed.
+        if queue.len() > 1 {
+            Ok(queue.pop_front().unwrap())
+        } else {
+            Ok(queue.front().unwrap().clone())
+        }
+    }
+}
+
+// FNV-1a hash of the serialized body. Unlike the standard library hashers, it is stable across
+// Rust releases so cassettes remain valid.
+pub fn key(body: &request::Body) -> String {
+    let json = serde_json::to_string(body).unwrap();
+    let hash = json.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+
+    format!("{:016x}", hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::mock::{chapter_response, last_content, ScriptedBackend};
+    use crate::chat::tests::user_message;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn cassette_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "story_teller_{}_{}.jsonl",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn body(content: &str) -> request::Body {
+        let mut message = user_message();
+        message.content = Some(content.to_string());
+
+        request::Body {
+            messages: vec![message],
+            ..Default::default()
+        }
+    }
+
+    fn echo_backend() -> ScriptedBackend {
+        ScriptedBackend::new(|body| Ok(chapter_response(&last_content(body), &["a", "b"], 10)))
+    }
+
+    #[test]
+    fn test_key_depends_on_body() {
+        assert_eq!(key(&body("foo")), key(&body("foo")));
+        assert_ne!(key(&body("foo")), key(&body("bar")));
+    }
+
+    #[tokio::test]
+    async fn test_record_then_replay() {
+        let path = cassette_path("record_then_replay");
+        let recorder = Recorder::new(echo_backend(), &path).unwrap();
+
+        recorder.submit(body("foo")).await.unwrap();
+        recorder.submit(body("bar")).await.unwrap();
+
+        let replayer = Replayer::open(&path).unwrap();
+        let response = replayer.submit(body("bar")).await.unwrap();
+        let message = response.message().unwrap();
+        let arguments = &message.tool_call("chapter").unwrap().arguments;
+
+        assert!(arguments.contains("\"text\":\"bar\""));
+        assert!(matches!(
+            replayer.submit(body("baz")).await,
+            Err(Error::NotRecorded(_))
+        ));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_replay_identical_requests_in_order() {
+        let path = cassette_path("identical_requests");
+        let count = AtomicU32::new(0);
+        let scripted = ScriptedBackend::new(move |_| {
+            let count = count.fetch_add(1, Ordering::SeqCst) + 1;
+            Ok(chapter_response(&count.to_string(), &["a", "b"], 10))
+        });
+        let recorder = Recorder::new(scripted, &path).unwrap();
+
+        recorder.submit(body("foo")).await.unwrap();
+        recorder.submit(body("foo")).await.unwrap();
+
+        let replayer = Replayer::open(&path).unwrap();
+        let mut texts = Vec::new();
+        for _ in 0..3 {
+            let response = replayer.submit(body("foo")).await.unwrap();
+            let message = response.message().unwrap();
+            texts.push(message.tool_call("chapter").unwrap().arguments.clone());
+        }
+
+        assert!(texts[0].contains("\"text\":\"1\""));
+        assert!(texts[1].contains("\"text\":\"2\""));
+        assert_eq!(texts[1], texts[2]);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,524 @@
+use crate::chat::request::{Body, Function, FunctionCall as Call, ToolChoice};
+use crate::chat::stream::{Delta, FunctionCallDelta, ToolCallDelta};
+use crate::chat::{json, ApiResponse, ChatBackend, FunctionCall, Message, Role, ToolCall};
+use crate::error::{Error, Result};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FunctionMode {
+    // Functions are sent to the server as tools.
+    #[default]
+    Tools,
+    // Functions are sent with the legacy functions and function_call fields, for older models.
+    Functions,
+    // Functions are described in the prompt and their arguments read from the content.
+    Prompt,
+    // Tools until the server rejects them, prompted from then on.
+    Auto,
+}
+
+// Adapts requests calling a function to what the server supports. The narrator always asks for
+// tools; older models get legacy functions instead, and servers without function calling
+// support are asked to reply with the JSON arguments, which are validated against the function
+// schema. Either way the answer is handed back as a tool call.
+#[derive(Clone)]
+pub struct FunctionCalling<B: ChatBackend> {
+    backend: B,
+    mode: FunctionMode,
+    // Set once the server has rejected a request with tools, in auto mode.
+    unsupported: Arc<AtomicBool>,
+}
+
+impl<B: ChatBackend> FunctionCalling<B> {
+    pub fn new(backend: B, mode: FunctionMode) -> Self {
+        Self {
+            backend,
+            mode,
+            unsupported: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    // Requests without tools are sent as they are.
+    fn mode(&self, body: &Body) -> FunctionMode {
+        match self.mode {
+            _ if body.tools.is_none() => FunctionMode::Tools,
+            FunctionMode::Auto if self.unsupported.load(Ordering::SeqCst) => FunctionMode::Prompt,
+            mode => mode,
+        }
+    }
+
+    // Whether a failed request with tools should be sent again with a prompt. Only errors about
+    // the tools themselves count, not a wrong key, model or rate limit.
+    fn fall_back(&self, body: &Body, error: &Error) -> bool {
+        if self.mode(body) == FunctionMode::Auto && rejects_tools(error) {
+            eprintln!("Tools rejected, falling back to prompting: {}", error);
+            self.unsupported.store(true, Ordering::SeqCst);
+            return true;
+        }
+        false
+    }
+}
+
+fn rejects_tools(error: &Error) -> bool {
+    use reqwest::StatusCode;
+
+    match error {
+        Error::Http { status, body } => {
+            let unrelated = [
+                StatusCode::UNAUTHORIZED,
+                StatusCode::FORBIDDEN,
+                StatusCode::NOT_FOUND,
+                StatusCode::TOO_MANY_REQUESTS,
+            ];
+            let body = body.to_lowercase();
+            status.is_client_error()
+                && !unrelated.contains(status)
+                && ["tools", "functions", "tool_choice"]
+                    .iter()
+                    .any(|field| body.contains(field))
+        }
+        _ => false,
+    }
+}
+
+impl<B: ChatBackend> ChatBackend for FunctionCalling<B> {
+    async fn submit(&self, body: Body) -> Result<ApiResponse> {
+        match self.mode(&body) {
+            FunctionMode::Functions => {
+                return from_legacy(self.backend.submit(legacy(&body)).await?);
+            }
+            FunctionMode::Tools | FunctionMode::Auto => {
+                match self.backend.submit(body.clone()).await {
+                    Ok(api_response) => return salvage(&body, api_response),
+                    Err(error) if self.fall_back(&body, &error) => (),
+                    Err(error) => return Err(error),
+                }
+            }
+            FunctionMode::Prompt => (),
+        }
+
+        let function = called_function(&body)?;
+        let api_response = self.backend.submit(prompt(&body, &function)).await?;
+        into_tool_call(&function, api_response)
+    }
+
+    async fn stream(
+        &self,
+        body: Body,
+        mut on_delta: impl FnMut(&Delta) + Send,
+    ) -> Result<ApiResponse> {
+        match self.mode(&body) {
+            FunctionMode::Functions => {
+                let api_response = self.backend.stream(legacy(&body), on_delta).await?;
+                return from_legacy(api_response);
+            }
+            FunctionMode::Tools | FunctionMode::Auto => {
+                match self.backend.stream(body.clone(), &mut on_delta).await {
+                    Ok(api_response) => return salvage(&body, api_response),
+                    Err(error) if self.fall_back(&body, &error) => (),
+                    Err(error) => return Err(error),
+                }
+            }
+            FunctionMode::Prompt => (),
+        }
+
+        // The content is reported as the arguments of the function being called
+        let function = called_function(&body)?;
+        let api_response = self
+            .backend
+            .stream(prompt(&body, &function), |delta| {
+                on_delta(&Delta {
+                    tool_calls: Some(vec![ToolCallDelta {
+                        index: 0,
+                        id: None,
+                        function: Some(FunctionCallDelta {
+                            name: None,
+                            arguments: delta.content.clone(),
+                        }),
+                    }]),
+                    ..Default::default()
+                })
+            })
+            .await?;
+        into_tool_call(&function, api_response)
+    }
+}
+
+fn called_function(body: &Body) -> Result<Function> {
+    let mut functions = body.tools.iter().flatten().map(|tool| &tool.function);
+    let function = match &body.tool_choice {
+        Some(ToolChoice::Function(name)) => functions.find(|function| function.name == *name),
+        _ => functions.next(),
+    };
+
+    function.cloned().ok_or(Error::MissingFunctionCall)
+}
+
+// Legacy calls have no identifier, the function name is used instead.
+fn call_id(function_call: &FunctionCall) -> String {
+    format!("call_{}", function_call.name)
+}
+
+fn legacy(body: &Body) -> Body {
+    let functions = body
+        .tools
+        .iter()
+        .flatten()
+        .map(|tool| tool.function.clone())
+        .collect();
+    let function_call = body
+        .tool_choice
+        .as_ref()
+        .map(|tool_choice| match tool_choice {
+            ToolChoice::None => Call::None,
+            ToolChoice::Auto | ToolChoice::Required => Call::Auto,
+            ToolChoice::Function(name) => Call::Name(name),
+        });
+
+    Body {
+        messages: legacy_messages(&body.messages),
+        tools: None,
+        tool_choice: None,
+        functions: Some(functions),
+        function_call,
+        ..body.clone()
+    }
+}
+
+// Older models only know one function call per message, answered by a function message.
+fn legacy_messages(messages: &[Message]) -> Vec<Message> {
+    let mut names: HashMap<&str, &str> = HashMap::new();
+
+    messages
+        .iter()
+        .map(|message| match (&message.role, &message.tool_calls) {
+            (Role::Assistant, Some(tool_calls)) => {
+                for tool_call in tool_calls {
+                    names.insert(&tool_call.id, &tool_call.function.name);
+                }
+                Message {
+                    function_call: tool_calls.first().map(|call| call.function.clone()),
+                    tool_calls: None,
+                    ..message.clone()
+                }
+            }
+            (Role::Tool, _) => {
+                let id = message.tool_call_id.as_deref().unwrap_or_default();
+                Message {
+                    name: names.get(id).map(|name| name.to_string()),
+                    tool_call_id: None,
+                    ..Message::new(Role::Function, message.content.clone())
+                }
+            }
+            _ => message.clone(),
+        })
+        .collect()
+}
+
+// Without function calling, calls are written as their JSON arguments and answered by the
+// user.
+fn prompted_messages(messages: &[Message]) -> Vec<Message> {
+    messages
+        .iter()
+        .map(|message| match (&message.role, &message.tool_calls) {
+            (Role::Assistant, Some(tool_calls)) => {
+                let arguments = tool_calls
+                    .iter()
+                    .map(|call| call.function.arguments.as_str());
+                Message::new(
+                    Role::Assistant,
+                    Some(arguments.collect::<Vec<_>>().join("\n")),
+                )
+            }
+            (Role::Tool, _) => Message::new(Role::User, message.content.clone()),
+            _ => message.clone(),
+        })
+        .collect()
+}
+
+fn from_legacy(mut api_response: ApiResponse) -> Result<ApiResponse> {
+    for choice in &mut api_response.choices {
+        let message = &mut choice.message;
+
+        if let Some(function_call) = message.function_call.take() {
+            message.tool_calls = Some(vec![ToolCall::new(call_id(&function_call), function_call)]);
+        }
+    }
+    Ok(api_response)
+}
+
+fn prompt(body: &Body, function: &Function) -> Body {
+    let schema = function.parameters.clone().unwrap_or(Value::Null);
+    let mut instructions = format!(
+        "Respond only with a JSON object, the arguments of the function `{}`",
+        function.name
+    );
+    if let Some(description) = function.description {
+        instructions.push_str(&format!(" ({})", description));
+    }
+    instructions.push_str(&format!(", matching this JSON schema:\n{}", schema));
+
+    let mut messages = prompted_messages(&body.messages);
+    messages.push(Message::new(Role::User, Some(instructions)));
+
+    Body {
+        messages,
+        tools: None,
+        tool_choice: None,
+        ..body.clone()
+    }
+}
+
+// Turns the JSON content of a prompted reply into a call of the function.
+fn into_tool_call(function: &Function, mut api_response: ApiResponse) -> Result<ApiResponse> {
+    let content = api_response
+        .message()?
+        .content
+        .ok_or(Error::EmptyResponse)?;
+    let arguments = json::extract_object(&content).ok_or(Error::MissingFunctionCall)?;
+
+    function.validate(&arguments)?;
+
+    let function_call = FunctionCall {
+        name: function.name.to_string(),
+        arguments: arguments.to_string(),
+    };
+    let message = &mut api_response.choices[0].message;
+    message.content = None;
+    message.tool_calls = Some(vec![ToolCall::new(call_id(&function_call), function_call)]);
+    Ok(api_response)
+}
+
+// Some servers accept tools but reply with the arguments in the content.
+fn salvage(body: &Body, api_response: ApiResponse) -> Result<ApiResponse> {
+    let message = api_response.message()?;
+    if body.tools.is_none() || message.tool_calls.is_some() || message.content.is_none() {
+        return Ok(api_response);
+    }
+
+    match called_function(body) {
+        Ok(function) => into_tool_call(&function, api_response.clone()).or(Ok(api_response)),
+        Err(_) => Ok(api_response),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::mock::{chapter_response, last_content, summary_response, ScriptedBackend};
+    use crate::chat::request::Tool;
+    use crate::chat::tests::user_message;
+    use crate::chat::{Choice, Usage};
+    use serde_json::json;
+
+    fn chapter_body() -> Body {
+        let parameters = json!({
+            "type": "object",
+            "properties": { "text": { "type": "string" } },
+            "required": ["text"],
+        });
+        Body {
+            messages: vec![user_message()],
+            tools: Some(vec![Tool::function(
+                Function::new("chapter", None, Some(parameters)).unwrap(),
+            )]),
+            tool_choice: Some(ToolChoice::Function("chapter")),
+            ..Default::default()
+        }
+    }
+
+    fn content_response(content: &str) -> ApiResponse {
+        ApiResponse {
+            choices: vec![Choice {
+                message: Message::new(Role::Assistant, Some(content.to_string())),
+            }],
+            usage: Usage {
+                total_tokens: 1,
+                ..Default::default()
+            },
+        }
+    }
+
+    fn arguments(api_response: &ApiResponse) -> Value {
+        let message = api_response.message().unwrap();
+        serde_json::from_str(&message.tool_call("chapter").unwrap().arguments).unwrap()
+    }
+
+    #[tokio::test]
+    async fn it_prompts_for_the_function_arguments() {
+        let backend = ScriptedBackend::new(|_| {
+            Ok(content_response(
+                "Sure!\n```json\n{\"text\": \"Once\"}\n```",
+            ))
+        });
+        let functions = FunctionCalling::new(backend.clone(), FunctionMode::Prompt);
+
+        let api_response = functions.submit(chapter_body()).await.unwrap();
+
+        assert_eq!(arguments(&api_response), json!({ "text": "Once" }));
+        let sent = &backend.requests()[0];
+        assert!(sent.tools.is_none() && sent.tool_choice.is_none());
+        assert_eq!(sent.messages.len(), 2);
+        assert!(last_content(sent).contains("\"required\":[\"text\"]"));
+    }
+
+    #[tokio::test]
+    async fn it_rejects_arguments_not_matching_the_schema() {
+        let backend = ScriptedBackend::new(|_| Ok(content_response("{\"text\": 3}")));
+        let functions = FunctionCalling::new(backend, FunctionMode::Prompt);
+
+        let result = functions.submit(chapter_body()).await;
+
+        assert!(matches!(result, Err(Error::SchemaViolation(_))));
+    }
+
+    #[tokio::test]
+    async fn it_streams_the_content_as_arguments() {
+        let backend = ScriptedBackend::new(|_| Ok(content_response("{\"text\": \"Once\"}")));
+        let functions = FunctionCalling::new(backend, FunctionMode::Prompt);
+        let mut streamed = String::new();
+
+        functions
+            .stream(chapter_body(), |delta| {
+                streamed.push_str(delta.arguments().unwrap());
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(streamed, "{\"text\": \"Once\"}");
+    }
+
+    #[tokio::test]
+    async fn it_falls_back_to_prompting_when_tools_are_rejected() {
+        let backend = ScriptedBackend::new(|body| match body.tools {
+            Some(_) => Err(Error::Http {
+                status: reqwest::StatusCode::BAD_REQUEST,
+                body: String::from("tools are not supported"),
+            }),
+            None => Ok(content_response("{\"text\": \"Once\"}")),
+        });
+        let functions = FunctionCalling::new(backend.clone(), FunctionMode::Auto);
+
+        functions.submit(chapter_body()).await.unwrap();
+        let api_response = functions.submit(chapter_body()).await.unwrap();
+
+        assert_eq!(arguments(&api_response), json!({ "text": "Once" }));
+        // The server is only asked for tools once
+        let native = backend
+            .requests()
+            .iter()
+            .filter(|body| body.tools.is_some())
+            .count();
+        assert_eq!(native, 1);
+    }
+
+    #[tokio::test]
+    async fn it_keeps_the_tools_on_unrelated_errors() {
+        let errors = [
+            (reqwest::StatusCode::UNAUTHORIZED, "invalid key for tools"),
+            (reqwest::StatusCode::BAD_REQUEST, "max_tokens is too large"),
+        ];
+
+        for (status, message) in errors {
+            let backend = ScriptedBackend::new(move |_| {
+                Err(Error::Http {
+                    status,
+                    body: message.to_string(),
+                })
+            });
+            let functions = FunctionCalling::new(backend.clone(), FunctionMode::Auto);
+
+            let result = functions.submit(chapter_body()).await;
+
+            assert!(matches!(result, Err(Error::Http { .. })));
+            assert_eq!(backend.requests().len(), 1);
+            assert!(!functions.unsupported.load(Ordering::SeqCst));
+        }
+    }
+
+    #[tokio::test]
+    async fn it_leaves_native_calls_and_other_requests_alone() {
+        let backend = ScriptedBackend::new(|body| match body.tools {
+            Some(_) => Ok(chapter_response("Once", &["a", "b"], 1)),
+            None => Ok(summary_response("Summary", 1)),
+        });
+        let functions = FunctionCalling::new(backend.clone(), FunctionMode::Auto);
+
+        let api_response = functions.submit(chapter_body()).await.unwrap();
+        assert_eq!(arguments(&api_response)["text"], "Once");
+
+        let summary = Body {
+            messages: vec![user_message()],
+            ..Default::default()
+        };
+        let api_response = functions.submit(summary).await.unwrap();
+        assert!(api_response.message().unwrap().tool_calls.is_none());
+    }
+
+    #[tokio::test]
+    async fn it_sends_legacy_functions_to_older_models() {
+        let backend = ScriptedBackend::new(|body| {
+            assert!(body.tools.is_none());
+            assert!(matches!(body.function_call, Some(Call::Name("chapter"))));
+            let function_call = FunctionCall {
+                name: String::from("chapter"),
+                arguments: String::from(r#"{"text":"Once"}"#),
+            };
+            Ok(ApiResponse {
+                choices: vec![Choice {
+                    message: Message {
+                        function_call: Some(function_call),
+                        ..Message::new(Role::Assistant, None)
+                    },
+                }],
+                usage: Usage {
+                    total_tokens: 1,
+                    ..Default::default()
+                },
+            })
+        });
+        let functions = FunctionCalling::new(backend.clone(), FunctionMode::Functions);
+
+        let api_response = functions.submit(chapter_body()).await.unwrap();
+
+        assert_eq!(arguments(&api_response), json!({ "text": "Once" }));
+        assert_eq!(backend.requests()[0].functions.as_ref().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn it_adapts_answered_calls_to_each_mode() {
+        let call = chapter_response("Once", &["a"], 1).message().unwrap();
+        let id = call.tool_calls.as_ref().unwrap()[0].id.clone();
+        let mut body = chapter_body();
+        let answer = Message::tool_result(&id, String::from("Too few choices"));
+        body.messages.extend([call, answer]);
+        let backend = ScriptedBackend::new(|_| Ok(content_response("{\"text\": \"Once\"}")));
+
+        let legacy = FunctionCalling::new(backend.clone(), FunctionMode::Functions);
+        legacy.submit(body.clone()).await.unwrap();
+        let prompted = FunctionCalling::new(backend.clone(), FunctionMode::Prompt);
+        prompted.submit(body).await.unwrap();
+
+        let requests = backend.requests();
+        let messages = &requests[0].messages;
+        assert!(messages[1].tool_calls.is_none());
+        assert_eq!(messages[1].function_call.as_ref().unwrap().name, "chapter");
+        assert_eq!(messages[2].role, Role::Function);
+        assert_eq!(messages[2].name.as_deref(), Some("chapter"));
+        assert!(messages[2].tool_call_id.is_none());
+
+        let messages = &requests[1].messages;
+        assert!(messages[1]
+            .content
+            .as_ref()
+            .unwrap()
+            .contains("\"choices\":[\"a\"]"));
+        assert_eq!(messages[2].role, Role::User);
+        assert_eq!(messages[2].content.as_deref(), Some("Too few choices"));
+    }
+}
@@ -0,0 +1,43 @@
+use serde_json::{Deserializer, Value};
+
+// Finds the first JSON object of a reply, which models sometimes wrap in markdown fences or
+// surround with comments despite being asked for JSON only.
+pub fn extract_object(content: &str) -> Option<Value> {
+    content.match_indices('{').find_map(|(start, _)| {
+        let mut values = Deserializer::from_str(&content[start..]).into_iter::<Value>();
+
+        match values.next() {
+            Some(Ok(value)) if value.is_object() => Some(value),
+            _ => None,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn it_reads_plain_json() {
+        let value = extract_object(r#"{"summary": "Once"}"#);
+
+        assert_eq!(value, Some(json!({ "summary": "Once" })));
+    }
+
+    #[test]
+    fn it_extracts_json_from_chatty_replies() {
+        let fenced = "Sure! Here is the {requested} summary:\n```json\n{\"summary\": \"Once {upon}\"}\n```\nEnjoy {it}.";
+
+        assert_eq!(
+            extract_object(fenced),
+            Some(json!({ "summary": "Once {upon}" }))
+        );
+    }
+
+    #[test]
+    fn it_finds_nothing_without_an_object() {
+        assert_eq!(extract_object("I cannot summarize this."), None);
+        assert_eq!(extract_object("{ not json"), None);
+    }
+}
@@ -0,0 +1,212 @@
+use crate::chat::{request::Body, stream::Delta, ApiResponse, ChatBackend, Usage};
+use crate::error::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+// Built-in prices in USD per million tokens, overridden by the configuration.
+const PRICES: &[(&str, Price)] = &[
+    ("gpt-3.5-turbo-0613", Price::new(1.5, 2.0)),
+    ("gpt-3.5-turbo", Price::new(0.5, 1.5)),
+    ("gpt-4o-mini", Price::new(0.15, 0.6)),
+    ("gpt-4o", Price::new(2.5, 10.0)),
+    ("gpt-4-turbo", Price::new(10.0, 30.0)),
+    ("gpt-4", Price::new(30.0, 60.0)),
+];
+
+// In USD per million tokens.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Price {
+    pub prompt: f64,
+    pub completion: f64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+pub struct ModelUsage {
+    pub requests: u32,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    // None while the price of the model is unknown.
+    pub cost: Option<f64>,
+}
+
+// Usage of every request of a story, shared by all clones, per model.
+#[derive(Clone, Default)]
+pub struct Ledger {
+    usage: Arc<Mutex<BTreeMap<String, ModelUsage>>>,
+    prices: Arc<BTreeMap<String, Price>>,
+    // In USD, for the whole story.
+    budget: Option<f64>,
+}
+
+// Records the usage of every response in the ledger, including requests the player never sees
+// the result of such as preloaded chapters and summaries.
+#[derive(Clone)]
+pub struct Metered<B: ChatBackend> {
+    backend: B,
+    ledger: Ledger,
+}
+
+impl Price {
+    pub const fn new(prompt: f64, completion: f64) -> Self {
+        Self { prompt, completion }
+    }
+
+    fn cost(&self, usage: &Usage) -> f64 {
+        let prompt = usage.prompt_tokens as f64 * self.prompt;
+        let completion = usage.completion_tokens as f64 * self.completion;
+        (prompt + completion) / 1_000_000.0
+    }
+}
+
+impl Ledger {
+    pub fn new(prices: BTreeMap<String, Price>, budget: Option<f64>) -> Self {
+        let mut all: BTreeMap<String, Price> = PRICES
+            .iter()
+            .map(|(model, price)| (model.to_string(), *price))
+            .collect();
+        all.extend(prices);
+
+        Self {
+            usage: Arc::default(),
+            prices: Arc::new(all),
+            budget,
+        }
+    }
+
+    pub fn record(&self, model: &str, usage: &Usage) {
+        let price = self.price(model);
+        let mut all = self.usage.lock().unwrap();
+        let entry = all.entry(model.to_string()).or_default();
+
+        if entry.requests == 0 {
+            entry.cost = price.map(|_| 0.0);
+        }
+        entry.requests += 1;
+        entry.prompt_tokens += usage.prompt_tokens as u64;
+        entry.completion_tokens += usage.completion_tokens as u64;
+        if let (Some(cost), Some(price)) = (&mut entry.cost, price) {
+            *cost += price.cost(usage);
+        }
+    }
+
+    pub fn usage(&self) -> BTreeMap<String, ModelUsage> {
+        self.usage.lock().unwrap().clone()
+    }
+
+    // Resumes the accounting of a saved story.
+    pub fn restore(&self, usage: BTreeMap<String, ModelUsage>) {
+        *self.usage.lock().unwrap() = usage;
+    }
+
+    // Models with an unknown price are not accounted for.
+    pub fn cost(&self) -> f64 {
+        let usage = self.usage.lock().unwrap();
+        usage.values().filter_map(|model| model.cost).sum()
+    }
+
+    pub fn budget(&self) -> Option<f64> {
+        self.budget
+    }
+
+    pub fn over_budget(&self) -> bool {
+        self.budget.is_some_and(|budget| self.cost() >= budget)
+    }
+
+    // Exact matches first, then the longest prefix so that dated versions share a price.
+    fn price(&self, model: &str) -> Option<Price> {
+        if let Some(price) = self.prices.get(model) {
+            return Some(*price);
+        }
+
+        self.prices
+            .iter()
+            .filter(|(prefix, _)| model.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, price)| *price)
+    }
+}
+
+impl<B: ChatBackend> Metered<B> {
+    pub fn new(backend: B, ledger: Ledger) -> Self {
+        Self { backend, ledger }
+    }
+}
+
+impl<B: ChatBackend> ChatBackend for Metered<B> {
+    async fn submit(&self, body: Body) -> Result<ApiResponse> {
+        let model = body.settings.model.clone();
+        let api_response = self.backend.submit(body).await?;
+
+        self.ledger.record(&model, &api_response.usage);
+        Ok(api_response)
+    }
+
+    async fn stream(&self, body: Body, on_delta: impl FnMut(&Delta) + Send) -> Result<ApiResponse> {
+        let model = body.settings.model.clone();
+        let api_response = self.backend.stream(body, on_delta).await?;
+
+        self.ledger.record(&model, &api_response.usage);
+        Ok(api_response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::mock::{chapter_response, ScriptedBackend};
+    use crate::chat::request::ModelSettings;
+
+    fn usage(prompt_tokens: u32, completion_tokens: u32) -> Usage {
+        Usage {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        }
+    }
+
+    #[test]
+    fn it_prices_usage_per_model() {
+        let prices = BTreeMap::from([(String::from("local"), Price::new(0.0, 0.0))]);
+        let ledger = Ledger::new(prices, Some(0.005));
+
+        ledger.record("gpt-4o-2024-08-06", &usage(1000, 500));
+        ledger.record("gpt-4o-2024-08-06", &usage(1000, 0));
+        ledger.record("local", &usage(5000, 5000));
+        ledger.record("mystery", &usage(10, 10));
+
+        let all = ledger.usage();
+        let gpt = &all["gpt-4o-2024-08-06"];
+        assert_eq!(
+            (gpt.requests, gpt.prompt_tokens, gpt.completion_tokens),
+            (2, 2000, 500)
+        );
+        assert!((ledger.cost() - 0.01).abs() < 1e-9);
+        assert_eq!(all["local"].cost, Some(0.0));
+        assert_eq!(all["mystery"].cost, None);
+        assert!(ledger.over_budget());
+    }
+
+    #[tokio::test]
+    async fn it_records_every_response() {
+        let ledger = Ledger::new(BTreeMap::new(), None);
+        let backend = Metered::new(
+            ScriptedBackend::new(|_| Ok(chapter_response("Once", &["a", "b"], 100))),
+            ledger.clone(),
+        );
+        let body = Body {
+            settings: ModelSettings {
+                model: String::from("gpt-4"),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        backend.submit(body.clone()).await.unwrap();
+        backend.stream(body, |_| ()).await.unwrap();
+
+        assert_eq!(ledger.usage()["gpt-4"].requests, 2);
+        assert!(!ledger.over_budget());
+    }
+}
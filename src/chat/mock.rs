@@ -0,0 +1,113 @@
+use crate::chat::{
+    request, ApiResponse, ChatBackend, Choice, FunctionCall, Message, Role, ToolCall, Usage,
+};
+use crate::error::Result;
+use serde_json::{json, Value};
+use std::sync::{Arc, Mutex};
+
+type Script = dyn Fn(&request::Body) -> Result<ApiResponse> + Send + Sync;
+
+// Offline backend answering every request with the script it was built with. Submitted bodies
+// are kept so tests can inspect what the narrator sent.
+#[derive(Clone)]
+pub struct ScriptedBackend {
+    script: Arc<Script>,
+    requests: Arc<Mutex<Vec<request::Body>>>,
+}
+
+impl ScriptedBackend {
+    pub fn new(
+        script: impl Fn(&request::Body) -> Result<ApiResponse> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            script: Arc::new(script),
+            requests: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    // Deterministic story: each chapter is named after the prompt that requested it and offers
+    // two choices derived from that name. Requests without tools are summary requests.
+    // Reported token usage grows with the number of messages sent.
+    pub fn story(tokens_per_message: u32) -> Self {
+        Self::new(move |body| {
+            let total_tokens = tokens_per_message * body.messages.len() as u32;
+
+            if body.tools.is_none() {
+                return Ok(summary_response("SUMMARY", total_tokens));
+            }
+
+            let content = last_content(body);
+            let prompt = content.trim();
+            let text = format!("After {}", prompt);
+            let choices = [format!("{} A", prompt), format!("{} B", prompt)];
+            Ok(chapter_response(&text, &choices, total_tokens))
+        })
+    }
+
+    pub fn requests(&self) -> Vec<request::Body> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+impl ChatBackend for ScriptedBackend {
+    async fn submit(&self, body: request::Body) -> Result<ApiResponse> {
+        let response = (self.script)(&body);
+        self.requests.lock().unwrap().push(body);
+        response
+    }
+}
+
+pub fn last_content(body: &request::Body) -> String {
+    body.messages
+        .last()
+        .and_then(|message| message.content.clone())
+        .unwrap_or_default()
+}
+
+pub fn chapter_response<S: AsRef<str>>(
+    text: &str,
+    choices: &[S],
+    total_tokens: u32,
+) -> ApiResponse {
+    let choices: Vec<&str> = choices.iter().map(AsRef::as_ref).collect();
+
+    function_response(json!({ "text": text, "choices": choices }), total_tokens)
+}
+
+pub fn function_response(arguments: Value, total_tokens: u32) -> ApiResponse {
+    response(
+        Message {
+            tool_calls: Some(vec![ToolCall::new(
+                String::from("call_chapter"),
+                FunctionCall {
+                    name: String::from("chapter"),
+                    arguments: arguments.to_string(),
+                },
+            )]),
+            ..Message::new(Role::Assistant, None)
+        },
+        total_tokens,
+    )
+}
+
+pub fn summary_response(summary: &str, total_tokens: u32) -> ApiResponse {
+    response(
+        Message::new(
+            Role::Assistant,
+            Some(json!({ "summary": summary }).to_string()),
+        ),
+        total_tokens,
+    )
+}
+
+fn response(message: Message, total_tokens: u32) -> ApiResponse {
+    ApiResponse {
+        choices: vec![Choice { message }],
+        // Every token is counted as a prompt token
+        usage: Usage {
+            prompt_tokens: total_tokens,
+            completion_tokens: 0,
+            total_tokens,
+        },
+    }
+}
@@ -1,20 +1,91 @@
-use crate::chat::Message;
+use crate::chat::{Message, ToolKind};
+use crate::error::{Error, Result};
 use jsonschema::JSONSchema;
-use serde::Serialize;
+use serde::{Deserialize, Serialize, Serializer};
 use serde_json::Value;
+use std::fmt;
+use std::sync::Arc;
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Debug, Clone, Default)]
 pub struct Body {
-    pub model: String,
+    #[serde(flatten)]
+    pub settings: ModelSettings,
     pub messages: Vec<Message>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Vec<Tool>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<ToolChoice>,
+    // Legacy equivalents of tools and tool_choice, for older models.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub functions: Option<Vec<Function>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub function_call: Option<FunctionCall>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<ResponseFormat>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_options: Option<StreamOptions>,
+}
+
+// Model and sampling parameters. Each task of the narrator can use its own. Parameters left
+// unset use the provider defaults.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModelSettings {
+    pub model: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    // Only known locally, for models missing from the built-in table.
+    #[serde(skip_serializing)]
+    pub context_window: Option<u32>,
+    // Only known locally, how replies expected as JSON are constrained.
+    #[serde(skip_serializing)]
+    pub json_format: JsonFormat,
+}
+
+// Servers supporting neither are only told about the JSON in the prompt.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum JsonFormat {
+    None,
+    JsonObject,
+    #[default]
+    JsonSchema,
+}
+
+// Constrains the content of the reply, to any JSON object or to one matching a schema.
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    JsonObject,
+    JsonSchema { json_schema: JsonSchema },
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct JsonSchema {
+    pub name: &'static str,
+    pub schema: Value,
+    pub strict: bool,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct StreamOptions {
+    pub include_usage: bool,
 }
 
 #[allow(dead_code)]
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Debug, Clone)]
 pub enum FunctionCall {
     #[serde(rename = "none")]
     None,
@@ -24,22 +95,70 @@ pub enum FunctionCall {
     Name(&'static str),
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Debug, Clone)]
+pub struct Tool {
+    #[serde(rename = "type")]
+    pub kind: ToolKind,
+    pub function: Function,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum ToolChoice {
+    None,
+    Auto,
+    Required,
+    Function(&'static str),
+}
+
+#[derive(Serialize, Clone)]
 pub struct Function {
     pub name: &'static str,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub description: Option<&'static str>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub parameters: Option<Value>,
+    // Compiled once from the parameters to validate the arguments of every call.
+    #[serde(skip)]
+    schema: Option<Arc<JSONSchema>>,
 }
 
-impl Default for Body {
+impl Default for ModelSettings {
     fn default() -> Self {
         Self {
             model: String::from("gpt-3.5-turbo-0613"),
-            messages: Vec::new(),
-            functions: None,
-            function_call: None,
+            temperature: None,
+            top_p: None,
+            max_tokens: None,
+            presence_penalty: None,
+            frequency_penalty: None,
+            seed: None,
+            context_window: None,
+            json_format: JsonFormat::default(),
+        }
+    }
+}
+
+impl Tool {
+    pub fn function(function: Function) -> Self {
+        Self {
+            kind: ToolKind::Function,
+            function,
+        }
+    }
+}
+
+impl Serialize for ToolChoice {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        match self {
+            ToolChoice::None => serializer.serialize_str("none"),
+            ToolChoice::Auto => serializer.serialize_str("auto"),
+            ToolChoice::Required => serializer.serialize_str("required"),
+            ToolChoice::Function(name) => {
+                let function =
+                    serde_json::json!({ "type": "function", "function": { "name": name } });
+                function.serialize(serializer)
+            }
         }
     }
 }
@@ -49,19 +168,51 @@ impl Function {
         name: &'static str,
         description: Option<&'static str>,
         parameters: Option<Value>,
-    ) -> Result<Self, &'static str> {
-        if let Some(value) = &parameters {
-            if JSONSchema::compile(value).is_err() {
-                return Err("Invalid JSON schema");
-            }
-        }
+    ) -> std::result::Result<Self, &'static str> {
+        let schema = match &parameters {
+            Some(value) => match JSONSchema::compile(value) {
+                Ok(schema) => Some(Arc::new(schema)),
+                Err(_) => return Err("Invalid JSON schema"),
+            },
+            None => None,
+        };
 
         Ok(Self {
             name,
             description,
             parameters,
+            schema,
         })
     }
+
+    // Checks the arguments of a call against the parameters schema, reporting every violation.
+    pub fn validate(&self, arguments: &Value) -> Result<()> {
+        let schema = match &self.schema {
+            Some(schema) => schema,
+            None => return Ok(()),
+        };
+
+        if let Err(errors) = schema.validate(arguments) {
+            let violations: Vec<String> = errors
+                .map(|error| match error.instance_path.to_string().as_str() {
+                    "" => error.to_string(),
+                    path => format!("{}: {}", path, error),
+                })
+                .collect();
+            return Err(Error::SchemaViolation(violations.join(", ")));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Function")
+            .field("name", &self.name)
+            .field("description", &self.description)
+            .field("parameters", &self.parameters)
+            .finish()
+    }
 }
 
 #[cfg(test)]
@@ -70,13 +221,21 @@ mod tests {
     use crate::chat::tests::user_message;
     use serde_json::json;
 
+    fn settings(model: &str) -> ModelSettings {
+        ModelSettings {
+            model: String::from(model),
+            ..Default::default()
+        }
+    }
+
     #[test]
     fn test_request_body_serialization() {
         let body = Body {
-            model: String::from("gpt-3.5-turbo"),
+            settings: settings("gpt-3.5-turbo"),
             messages: vec![user_message()],
             functions: None,
             function_call: None,
+            ..Default::default()
         };
 
         let json = serde_json::to_string(&body).unwrap();
@@ -87,12 +246,34 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_sampling_parameters_serialization() {
+        let body = Body {
+            settings: ModelSettings {
+                temperature: Some(0.5),
+                max_tokens: Some(500),
+                seed: Some(42),
+                ..settings("gpt-4o")
+            },
+            messages: vec![user_message()],
+            ..Default::default()
+        };
+
+        let json = serde_json::to_string(&body).unwrap();
+
+        assert_eq!(
+            json,
+            r#"{"model":"gpt-4o","temperature":0.5,"max_tokens":500,"seed":42,"messages":[{"role":"user","content":"Hello, world!"}]}"#
+        );
+    }
+
     #[test]
     fn test_function_serialization() {
         let function = Function {
             name: "foo",
             description: Some("Stuff"),
             parameters: None,
+            schema: None,
         };
 
         let json = serde_json::to_string(&function).unwrap();
@@ -108,6 +289,22 @@ mod tests {
         assert_eq!(json, r#"{"name":"foo","parameters":{"maxLength":5}}"#);
     }
 
+    #[test]
+    fn test_validate_arguments() {
+        let schema = json!({
+            "type": "object",
+            "properties": { "choices": { "type": "array", "minItems": 2 } },
+            "required": ["choices"],
+        });
+        let function = Function::new("foo", None, Some(schema)).unwrap();
+
+        assert!(function.validate(&json!({ "choices": [1, 2] })).is_ok());
+        match function.validate(&json!({ "choices": [1] })) {
+            Err(Error::SchemaViolation(reason)) => assert!(reason.starts_with("/choices: ")),
+            result => panic!("unexpected {:?}", result),
+        }
+    }
+
     #[test]
     fn test_invalid_json_format() {
         let schema = json!({"type": "invalidType"});
@@ -115,13 +312,54 @@ mod tests {
         assert!(function.is_err());
     }
 
+    #[test]
+    fn test_tools_serialization() {
+        let function = Function::new("foo", None, None).unwrap();
+        let body = Body {
+            settings: settings("gpt-4o"),
+            tools: Some(vec![Tool::function(function)]),
+            tool_choice: Some(ToolChoice::Function("foo")),
+            ..Default::default()
+        };
+
+        let json = serde_json::to_string(&body).unwrap();
+
+        assert_eq!(
+            json,
+            r#"{"model":"gpt-4o","messages":[],"tools":[{"type":"function","function":{"name":"foo"}}],"tool_choice":{"function":{"name":"foo"},"type":"function"}}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&ToolChoice::Required).unwrap(),
+            r#""required""#
+        );
+    }
+
+    #[test]
+    fn test_response_format_serialization() {
+        let json_object = serde_json::to_string(&ResponseFormat::JsonObject).unwrap();
+        assert_eq!(json_object, r#"{"type":"json_object"}"#);
+
+        let json_schema = ResponseFormat::JsonSchema {
+            json_schema: JsonSchema {
+                name: "summary",
+                schema: json!({ "type": "object" }),
+                strict: true,
+            },
+        };
+        assert_eq!(
+            serde_json::to_string(&json_schema).unwrap(),
+            r#"{"type":"json_schema","json_schema":{"name":"summary","schema":{"type":"object"},"strict":true}}"#
+        );
+    }
+
     #[test]
     fn test_function_call_none() {
         let body = Body {
-            model: String::from("gpt-3.5-turbo"),
+            settings: settings("gpt-3.5-turbo"),
             messages: vec![user_message()],
             functions: None,
             function_call: Some(FunctionCall::None),
+            ..Default::default()
         };
 
         let json = serde_json::to_string(&body).unwrap();
@@ -135,10 +373,11 @@ mod tests {
     #[test]
     fn test_function_call_with_name() {
         let body = Body {
-            model: String::from("gpt-3.5-turbo"),
+            settings: settings("gpt-3.5-turbo"),
             messages: vec![user_message()],
             functions: None,
             function_call: Some(FunctionCall::Name("foo")),
+            ..Default::default()
         };
 
         let json = serde_json::to_string(&body).unwrap();
@@ -0,0 +1,217 @@
+use rand::Rng;
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+use serde::{Deserialize, Deserializer};
+use std::time::{Duration, SystemTime};
+
+// Delays are given in seconds in the configuration.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    #[serde(deserialize_with = "deserialize_seconds")]
+    pub base_delay: Duration,
+    // Also bounds the delays asked for by the server.
+    #[serde(deserialize_with = "deserialize_seconds")]
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Exponential backoff with full jitter: a random delay up to base * 2^(attempt - 1).
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(16);
+        let ceiling = self
+            .base_delay
+            .saturating_mul(1 << exponent)
+            .min(self.max_delay);
+
+        ceiling.mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+    }
+
+    pub fn bound(&self, delay: Duration) -> Duration {
+        delay.min(self.max_delay)
+    }
+}
+
+pub fn is_transient(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::REQUEST_TIMEOUT
+        || status.is_server_error()
+}
+
+// Either a number of seconds or an HTTP date, values that make no sense are ignored.
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    if let Some(millis) = header(headers, "retry-after-ms").and_then(|v| v.parse::<f64>().ok()) {
+        return seconds(millis / 1000.0);
+    }
+
+    let value = header(headers, "retry-after")?.trim();
+    match value.parse::<f64>() {
+        Ok(number) => seconds(number),
+        Err(_) => {
+            let date = httpdate::parse_http_date(value).ok()?;
+            Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+        }
+    }
+}
+
+// Time to wait before the request or token quota is replenished, when it has been used up.
+pub fn rate_limit_reset(headers: &HeaderMap) -> Option<Duration> {
+    ["requests", "tokens"]
+        .iter()
+        .filter(|kind| header(headers, &format!("x-ratelimit-remaining-{}", kind)) == Some("0"))
+        .filter_map(|kind| header(headers, &format!("x-ratelimit-reset-{}", kind)))
+        .filter_map(parse_reset)
+        .max()
+}
+
+// Parses reset durations as sent by OpenAI, such as "20ms", "1.5s" or "6m0s".
+pub fn parse_reset(value: &str) -> Option<Duration> {
+    let mut total = 0.0;
+    let mut rest = value.trim();
+
+    if rest.is_empty() {
+        return None;
+    }
+
+    while !rest.is_empty() {
+        let split = rest.find(|c: char| c.is_ascii_alphabetic())?;
+        let number: f64 = rest[..split].parse().ok().filter(|n: &f64| *n >= 0.0)?;
+        rest = &rest[split..];
+
+        let unit_length = rest
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(rest.len());
+        let seconds = match &rest[..unit_length] {
+            "ms" => number / 1000.0,
+            "s" => number,
+            "m" => number * 60.0,
+            "h" => number * 3600.0,
+            _ => return None,
+        };
+
+        total += seconds;
+        rest = &rest[unit_length..];
+    }
+
+    seconds(total)
+}
+
+// Server-controlled values may be negative, infinite or too large for a duration.
+fn seconds(value: f64) -> Option<Duration> {
+    match value.is_finite() && value >= 0.0 {
+        true => Duration::try_from_secs_f64(value).ok(),
+        false => None,
+    }
+}
+
+fn deserialize_seconds<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let value = f64::deserialize(deserializer)?;
+    seconds(value).ok_or_else(|| serde::de::Error::custom("expected a positive number of seconds"))
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn test_parse_reset() {
+        assert_eq!(parse_reset("20ms"), Some(Duration::from_millis(20)));
+        assert_eq!(parse_reset("1.5s"), Some(Duration::from_millis(1500)));
+        assert_eq!(parse_reset("6m0s"), Some(Duration::from_secs(360)));
+        assert_eq!(parse_reset("1h2m3s"), Some(Duration::from_secs(3723)));
+        assert_eq!(parse_reset("soon"), None);
+        assert_eq!(parse_reset(""), None);
+        assert_eq!(parse_reset("-1s"), None);
+        assert_eq!(parse_reset("1e300h"), None);
+    }
+
+    #[test]
+    fn test_retry_after() {
+        assert_eq!(
+            retry_after(&headers(&[("retry-after", "2")])),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(
+            retry_after(&headers(&[("retry-after", "2"), ("retry-after-ms", "150")])),
+            Some(Duration::from_millis(150))
+        );
+        assert_eq!(retry_after(&HeaderMap::new()), None);
+        assert_eq!(retry_after(&headers(&[("retry-after", "inf")])), None);
+        assert_eq!(retry_after(&headers(&[("retry-after", "1e30")])), None);
+        assert_eq!(retry_after(&headers(&[("retry-after", "-3")])), None);
+        assert_eq!(
+            retry_after(&headers(&[(
+                "retry-after",
+                "Wed, 21 Oct 2015 07:28:00 GMT"
+            )])),
+            Some(Duration::ZERO)
+        );
+
+        let date = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(120));
+        let mut later = HeaderMap::new();
+        later.insert("retry-after", HeaderValue::from_str(&date).unwrap());
+        let delay = retry_after(&later).unwrap();
+        assert!(delay > Duration::from_secs(100) && delay <= Duration::from_secs(120));
+        assert_eq!(RetryPolicy::default().bound(delay), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_rate_limit_reset_only_when_exhausted() {
+        let available = headers(&[
+            ("x-ratelimit-remaining-requests", "12"),
+            ("x-ratelimit-reset-requests", "1s"),
+        ]);
+        let exhausted = headers(&[
+            ("x-ratelimit-remaining-requests", "0"),
+            ("x-ratelimit-reset-requests", "1s"),
+            ("x-ratelimit-remaining-tokens", "0"),
+            ("x-ratelimit-reset-tokens", "6m0s"),
+        ]);
+
+        assert_eq!(rate_limit_reset(&available), None);
+        assert_eq!(rate_limit_reset(&exhausted), Some(Duration::from_secs(360)));
+    }
+
+    #[test]
+    fn test_backoff_is_bounded() {
+        let policy = RetryPolicy::default();
+
+        for attempt in 1..20 {
+            let ceiling = policy.base_delay * 2u32.pow(attempt.min(16) - 1);
+            let delay = policy.backoff(attempt);
+            assert!(delay <= ceiling.min(policy.max_delay));
+        }
+    }
+
+    #[test]
+    fn test_transient_statuses() {
+        assert!(is_transient(StatusCode::TOO_MANY_REQUESTS));
+        assert!(is_transient(StatusCode::BAD_GATEWAY));
+        assert!(!is_transient(StatusCode::BAD_REQUEST));
+        assert!(!is_transient(StatusCode::UNAUTHORIZED));
+    }
+}
@@ -0,0 +1,275 @@
+use crate::chat::{ApiResponse, Choice, FunctionCall, Message, Role, ToolCall, Usage};
+use crate::error::Result;
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug)]
+pub struct Chunk {
+    #[serde(default)]
+    pub choices: Vec<ChunkChoice>,
+    pub usage: Option<Usage>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ChunkChoice {
+    pub delta: Delta,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct Delta {
+    pub content: Option<String>,
+    pub function_call: Option<FunctionCallDelta>,
+    pub tool_calls: Option<Vec<ToolCallDelta>>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct ToolCallDelta {
+    #[serde(default)]
+    pub index: usize,
+    pub id: Option<String>,
+    pub function: Option<FunctionCallDelta>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct FunctionCallDelta {
+    pub name: Option<String>,
+    pub arguments: Option<String>,
+}
+
+// Splits a server-sent events body into the JSON payloads of its `data:` lines.
+#[derive(Default)]
+pub struct EventDecoder {
+    buffer: Vec<u8>,
+    done: bool,
+}
+
+// Rebuilds the complete response out of the streamed deltas.
+#[derive(Default)]
+pub struct Accumulator {
+    content: Option<String>,
+    function_call: Option<FunctionCall>,
+    tool_calls: Vec<ToolCall>,
+    usage: Option<Usage>,
+}
+
+impl EventDecoder {
+    pub fn push(&mut self, bytes: &[u8]) -> Result<Vec<Chunk>> {
+        self.buffer.extend_from_slice(bytes);
+        let mut chunks = Vec::new();
+
+        while let Some(position) = self.buffer.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=position).collect();
+            let line = String::from_utf8_lossy(&line);
+            let data = match line.trim().strip_prefix("data:") {
+                Some(data) => data.trim(),
+                None => continue,
+            };
+
+            if data == "[DONE]" {
+                self.done = true;
+            } else if !self.done {
+                chunks.push(serde_json::from_str(data)?);
+            }
+        }
+
+        Ok(chunks)
+    }
+}
+
+impl Delta {
+    pub fn from_message(message: &Message) -> Self {
+        let tool_calls = message.tool_calls.as_ref().map(|tool_calls| {
+            tool_calls
+                .iter()
+                .enumerate()
+                .map(|(index, tool_call)| ToolCallDelta {
+                    index,
+                    id: Some(tool_call.id.clone()),
+                    function: Some(FunctionCallDelta::from_call(&tool_call.function)),
+                })
+                .collect()
+        });
+
+        Self {
+            content: message.content.clone(),
+            function_call: message
+                .function_call
+                .as_ref()
+                .map(FunctionCallDelta::from_call),
+            tool_calls,
+        }
+    }
+
+    // Part of the arguments of the first function called, through tools or legacy functions.
+    pub fn arguments(&self) -> Option<&str> {
+        let tool_call = self
+            .tool_calls
+            .iter()
+            .flatten()
+            .find(|tool_call| tool_call.index == 0)
+            .and_then(|tool_call| tool_call.function.as_ref());
+
+        tool_call
+            .or(self.function_call.as_ref())
+            .and_then(|function_call| function_call.arguments.as_deref())
+    }
+}
+
+impl FunctionCallDelta {
+    fn from_call(function_call: &FunctionCall) -> Self {
+        Self {
+            name: Some(function_call.name.clone()),
+            arguments: Some(function_call.arguments.clone()),
+        }
+    }
+
+    fn apply(&self, function_call: &mut FunctionCall) {
+        if let Some(name) = &self.name {
+            function_call.name.push_str(name);
+        }
+        if let Some(arguments) = &self.arguments {
+            function_call.arguments.push_str(arguments);
+        }
+    }
+}
+
+impl Accumulator {
+    pub fn push(&mut self, chunk: &Chunk) {
+        if let Some(usage) = &chunk.usage {
+            self.usage = Some(usage.clone());
+        }
+
+        for choice in chunk.choices.iter().take(1) {
+            let delta = &choice.delta;
+
+            if let Some(content) = &delta.content {
+                self.content
+                    .get_or_insert_with(String::new)
+                    .push_str(content);
+            }
+
+            if let Some(function_call) = &delta.function_call {
+                function_call.apply(self.function_call.get_or_insert_with(empty_call));
+            }
+
+            for tool_call in delta.tool_calls.iter().flatten() {
+                while self.tool_calls.len() <= tool_call.index {
+                    self.tool_calls
+                        .push(ToolCall::new(String::new(), empty_call()));
+                }
+                let accumulated = &mut self.tool_calls[tool_call.index];
+
+                if let Some(id) = &tool_call.id {
+                    accumulated.id.push_str(id);
+                }
+                if let Some(function) = &tool_call.function {
+                    function.apply(&mut accumulated.function);
+                }
+            }
+        }
+    }
+
+    pub fn finish(self) -> ApiResponse {
+        let message = Message {
+            function_call: self.function_call,
+            tool_calls: (!self.tool_calls.is_empty()).then_some(self.tool_calls),
+            ..Message::new(Role::Assistant, self.content)
+        };
+
+        ApiResponse {
+            choices: vec![Choice { message }],
+            usage: self.usage.unwrap_or_default(),
+        }
+    }
+}
+
+fn empty_call() -> FunctionCall {
+    FunctionCall {
+        name: String::new(),
+        arguments: String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &str = concat!(
+        "data: {\"choices\":[{\"delta\":{\"role\":\"assistant\",\"function_call\":{\"name\":\"chapter\",\"arguments\":\"\"}}}]}\n\n",
+        "data: {\"choices\":[{\"delta\":{\"function_call\":{\"arguments\":\"{\\\"text\\\":\"}}}]}\n\n",
+        "data: {\"choices\":[{\"delta\":{\"function_call\":{\"arguments\":\"\\\"Hi\\\"}\"}}}]}\n\n",
+        "data: {\"choices\":[],\"usage\":{\"total_tokens\":12}}\n\n",
+        "data: [DONE]\n\n",
+    );
+
+    #[test]
+    fn test_decode_events_split_across_reads() {
+        let mut decoder = EventDecoder::default();
+        let mut accumulator = Accumulator::default();
+
+        for part in BODY.as_bytes().chunks(7) {
+            for chunk in decoder.push(part).unwrap() {
+                accumulator.push(&chunk);
+            }
+        }
+
+        let response = accumulator.finish();
+        let function_call = response.message().unwrap().function_call.unwrap();
+        assert_eq!(function_call.name, "chapter");
+        assert_eq!(function_call.arguments, r#"{"text":"Hi"}"#);
+        assert_eq!(response.usage.total_tokens, 12);
+    }
+
+    #[test]
+    fn test_accumulate_tool_calls() {
+        let body = concat!(
+            "data: {\"choices\":[{\"delta\":{\"tool_calls\":[{\"index\":0,\"id\":\"call_1\",\"type\":\"function\",\"function\":{\"name\":\"chapter\",\"arguments\":\"\"}}]}}]}\n",
+            "data: {\"choices\":[{\"delta\":{\"tool_calls\":[{\"index\":0,\"function\":{\"arguments\":\"{\\\"text\\\":\"}}]}}]}\n",
+            "data: {\"choices\":[{\"delta\":{\"tool_calls\":[{\"index\":0,\"function\":{\"arguments\":\"\\\"Hi\\\"}\"}}]}}]}\n",
+        );
+        let mut decoder = EventDecoder::default();
+        let mut accumulator = Accumulator::default();
+        let mut arguments = String::new();
+
+        for chunk in decoder.push(body.as_bytes()).unwrap() {
+            arguments.push_str(chunk.choices[0].delta.arguments().unwrap());
+            accumulator.push(&chunk);
+        }
+
+        let message = accumulator.finish().message().unwrap();
+        let tool_calls = message.tool_calls.as_ref().unwrap();
+        assert_eq!(tool_calls.len(), 1);
+        assert_eq!(tool_calls[0].id, "call_1");
+        assert_eq!(message.tool_call("chapter").unwrap().arguments, arguments);
+        assert_eq!(arguments, r#"{"text":"Hi"}"#);
+    }
+
+    #[test]
+    fn test_ignore_comments_and_events() {
+        let mut decoder = EventDecoder::default();
+        let chunks = decoder
+            .push(b": keep-alive\nevent: message\ndata: {\"choices\":[]}\n")
+            .unwrap();
+
+        assert_eq!(chunks.len(), 1);
+    }
+
+    #[test]
+    fn test_accumulate_content() {
+        let mut accumulator = Accumulator::default();
+        for content in ["Hello", ", ", "world"] {
+            accumulator.push(&Chunk {
+                choices: vec![ChunkChoice {
+                    delta: Delta {
+                        content: Some(content.to_string()),
+                        ..Default::default()
+                    },
+                }],
+                usage: None,
+            });
+        }
+
+        let message = accumulator.finish().message().unwrap();
+        assert_eq!(message.content.as_deref(), Some("Hello, world"));
+        assert!(message.function_call.is_none());
+    }
+}
@@ -0,0 +1,161 @@
+use crate::chat::request::{Body, ModelSettings};
+use crate::chat::Message;
+use crate::error::{Error, Result};
+use serde::Serialize;
+use std::sync::OnceLock;
+use tiktoken_rs::{cl100k_base, CoreBPE};
+
+// Tokens the API adds around every message, and to prime the reply.
+const TOKENS_PER_MESSAGE: u32 = 3;
+const TOKENS_PER_REPLY: u32 = 3;
+// Room kept for the reply of requests without max_tokens.
+const DEFAULT_REPLY_TOKENS: u32 = 256;
+const DEFAULT_CONTEXT_WINDOW: u32 = 4_096;
+
+// Known models by prefix, the most specific first.
+const CONTEXT_WINDOWS: &[(&str, u32)] = &[
+    ("gpt-4o", 128_000),
+    ("gpt-4-turbo", 128_000),
+    ("gpt-4-1106-preview", 128_000),
+    ("gpt-4-0125-preview", 128_000),
+    ("gpt-4-32k", 32_768),
+    ("gpt-4", 8_192),
+    ("gpt-3.5-turbo-0613", 4_096),
+    ("gpt-3.5-turbo-16k", 16_385),
+    ("gpt-3.5-turbo", 16_385),
+];
+
+// The ranks are bundled in the binary and only parsed once.
+fn bpe() -> &'static CoreBPE {
+    static BPE: OnceLock<CoreBPE> = OnceLock::new();
+    BPE.get_or_init(|| cl100k_base().expect("Invalid bundled BPE ranks"))
+}
+
+pub fn count_text(text: &str) -> u32 {
+    bpe().encode_ordinary(text).len() as u32
+}
+
+pub fn count_message(message: &Message) -> u32 {
+    let function_calls = message.function_call.iter().chain(
+        message
+            .tool_calls
+            .iter()
+            .flatten()
+            .map(|call| &call.function),
+    );
+    let texts = function_calls
+        .flat_map(|call| [&call.name, &call.arguments])
+        .chain(&message.content)
+        .chain(&message.name);
+
+    TOKENS_PER_MESSAGE + texts.map(|text| count_text(text)).sum::<u32>()
+}
+
+pub fn count_messages(messages: &[Message]) -> u32 {
+    TOKENS_PER_REPLY + messages.iter().map(count_message).sum::<u32>()
+}
+
+// Estimated size of the prompt of a request, including the definitions sent along the
+// messages.
+pub fn count_prompt(body: &Body) -> u32 {
+    count_messages(&body.messages)
+        + count_json(&body.tools)
+        + count_json(&body.functions)
+        + count_json(&body.response_format)
+}
+
+pub fn context_window(settings: &ModelSettings) -> u32 {
+    let known = CONTEXT_WINDOWS
+        .iter()
+        .find(|(prefix, _)| settings.model.starts_with(prefix))
+        .map(|(_, tokens)| *tokens);
+
+    settings
+        .context_window
+        .or(known)
+        .unwrap_or(DEFAULT_CONTEXT_WINDOW)
+}
+
+// Refuses requests whose prompt and reply would not fit in the context window of the model,
+// before they are sent.
+pub fn check(body: &Body) -> Result<()> {
+    let reply = reply_tokens(&body.settings);
+    let tokens = count_prompt(body) + reply;
+    let limit = context_window(&body.settings);
+
+    match tokens > limit {
+        true => Err(Error::ContextWindowExceeded { tokens, limit }),
+        false => Ok(()),
+    }
+}
+
+// Room kept for the reply of a request.
+pub fn reply_tokens(settings: &ModelSettings) -> u32 {
+    settings.max_tokens.unwrap_or(DEFAULT_REPLY_TOKENS)
+}
+
+fn count_json<T: Serialize>(value: &Option<T>) -> u32 {
+    match value {
+        Some(value) => count_text(&serde_json::to_string(value).unwrap()),
+        None => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::tests::user_message;
+    use crate::chat::Role;
+
+    fn body(content: &str, settings: ModelSettings) -> Body {
+        Body {
+            settings,
+            messages: vec![Message::new(Role::User, Some(content.to_string()))],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn it_counts_the_tokens_of_messages() {
+        assert_eq!(count_text("Hello, world!"), 4);
+        assert_eq!(count_message(&user_message()), 7);
+        assert_eq!(count_messages(&[user_message(), user_message()]), 17);
+    }
+
+    #[test]
+    fn it_knows_the_context_window_of_models() {
+        let settings = |model: &str, context_window| ModelSettings {
+            model: model.to_string(),
+            context_window,
+            ..Default::default()
+        };
+
+        assert_eq!(context_window(&settings("gpt-4o-mini", None)), 128_000);
+        assert_eq!(context_window(&settings("gpt-4-0613", None)), 8_192);
+        assert_eq!(
+            context_window(&settings("gpt-4-1106-preview", None)),
+            128_000
+        );
+        assert_eq!(
+            context_window(&settings("gpt-4-0125-preview", None)),
+            128_000
+        );
+        assert_eq!(context_window(&settings("llama3", None)), 4_096);
+        assert_eq!(context_window(&settings("llama3", Some(8_192))), 8_192);
+    }
+
+    #[test]
+    fn it_refuses_requests_exceeding_the_context_window() {
+        let settings = ModelSettings {
+            max_tokens: Some(100),
+            context_window: Some(120),
+            ..Default::default()
+        };
+
+        assert!(check(&body("Hello, world!", settings.clone())).is_ok());
+        assert!(matches!(
+            check(&body(&"word ".repeat(20), settings)),
+            Err(Error::ContextWindowExceeded { limit: 120, .. })
+        ));
+    }
+}
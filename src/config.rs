@@ -0,0 +1,268 @@
+use crate::chat::{
+    functions::FunctionMode, metering::Price, request::ModelSettings, retry::RetryPolicy,
+};
+use crate::error::{Error, Result};
+use crate::narrator::{PreloadSettings, StorySetup};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml::{Table, Value};
+
+pub const DEFAULT_CONFIG_FILE: &str = "story_teller.toml";
+const ENV_PREFIX: &str = "STORY_TELLER__";
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub endpoint: String,
+    // Falls back to the OPENAI_API_KEY environment variable.
+    pub api_key: Option<String>,
+    pub functions: FunctionMode,
+    pub retry: RetryPolicy,
+    pub chapter: ModelSettings,
+    pub summary: ModelSettings,
+    // Per model, added to the built-in prices.
+    pub prices: BTreeMap<String, Price>,
+    // In USD. Chapters are no longer preloaded once the story has cost this much.
+    pub budget: Option<f64>,
+    pub preload: PreloadSettings,
+    // Of the dice rolled for checks, random if not set. Saved stories keep their own.
+    pub seed: Option<u64>,
+    // Skips the questions asked before a new story, even if empty.
+    pub setup: Option<StorySetup>,
+    // Directory of templates overriding the embedded prompts, by file name.
+    pub templates: Option<PathBuf>,
+    // Of the story, available to the templates as {language}.
+    pub language: String,
+}
+
+// Settings of every task the narrator asks the model for.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Models {
+    pub chapter: ModelSettings,
+    pub summary: ModelSettings,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            endpoint: String::from("https://api.openai.com/v1/chat/completions"),
+            api_key: None,
+            functions: FunctionMode::default(),
+            retry: RetryPolicy::default(),
+            chapter: ModelSettings::default(),
+            summary: ModelSettings::default(),
+            prices: BTreeMap::new(),
+            budget: None,
+            preload: PreloadSettings::default(),
+            seed: None,
+            setup: None,
+            templates: None,
+            language: String::from("English"),
+        }
+    }
+}
+
+impl Config {
+    // Reads the configuration file given with `--config <path>`, or the default one if it
+    // exists. Environment variables such as STORY_TELLER__CHAPTER__MODEL override it, and so do
+    // `--set chapter.model=<value>` arguments in turn.
+    pub fn load(
+        args: &[String],
+        vars: impl Iterator<Item = (String, String)>,
+        default_file: impl AsRef<Path>,
+    ) -> Result<Self> {
+        let mut table = match argument(args, "--config") {
+            Some(path) => read(path)?,
+            None if default_file.as_ref().exists() => read(default_file)?,
+            None => Table::new(),
+        };
+
+        for (name, value) in vars {
+            if let Some(key) = name.strip_prefix(ENV_PREFIX) {
+                set(&mut table, &key.to_lowercase().replace("__", "."), &value)?;
+            }
+        }
+
+        for assignment in arguments(args, "--set") {
+            let (key, value) = assignment.split_once('=').ok_or_else(|| {
+                Error::InvalidConfig(format!("expected key=value, got {}", assignment))
+            })?;
+            set(&mut table, key.trim(), value.trim())?;
+        }
+
+        table
+            .try_into()
+            .map_err(|error: toml::de::Error| Error::InvalidConfig(error.to_string()))
+    }
+
+    pub fn models(&self) -> Models {
+        Models {
+            chapter: self.chapter.clone(),
+            summary: self.summary.clone(),
+        }
+    }
+}
+
+// The value following the last occurrence of a command line option.
+pub fn argument<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    arguments(args, name).pop()
+}
+
+fn arguments<'a>(args: &'a [String], name: &str) -> Vec<&'a str> {
+    args.windows(2)
+        .filter(|pair| pair[0] == name)
+        .map(|pair| pair[1].as_str())
+        .collect()
+}
+
+fn read(path: impl AsRef<Path>) -> Result<Table> {
+    let path = path.as_ref();
+    let content = fs::read_to_string(path)?;
+
+    toml::from_str(&content)
+        .map_err(|error| Error::InvalidConfig(format!("{}: {}", path.display(), error)))
+}
+
+// Sets a dotted key such as chapter.temperature. Values are read as TOML when possible so
+// numbers do not need quoting, and as plain strings otherwise.
+fn set(table: &mut Table, key: &str, raw: &str) -> Result<()> {
+    let value = toml::from_str::<Table>(&format!("value = {}", raw))
+        .ok()
+        .and_then(|mut parsed| parsed.remove("value"))
+        .unwrap_or_else(|| Value::String(raw.to_string()));
+
+    let mut parts: Vec<&str> = key.split('.').collect();
+    let last = parts.pop().filter(|last| !last.is_empty());
+    let last = last.ok_or_else(|| Error::InvalidConfig(format!("invalid key {}", key)))?;
+
+    let mut table = table;
+    for part in parts {
+        let entry = table
+            .entry(part)
+            .or_insert_with(|| Value::Table(Table::new()));
+        table = match entry {
+            Value::Table(nested) => nested,
+            _ => return Err(Error::InvalidConfig(format!("{} is not a table", part))),
+        };
+    }
+
+    table.insert(last.to_string(), value);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    // Independent of any configuration file in the working directory.
+    fn load(args: &[String], vars: impl Iterator<Item = (String, String)>) -> Result<Config> {
+        Config::load(args, vars, Path::new("missing/story_teller.toml"))
+    }
+
+    #[test]
+    fn it_defaults_to_openai() {
+        let config = load(&[], std::iter::empty()).unwrap();
+
+        assert_eq!(config, Config::default());
+        assert_eq!(config.chapter.model, "gpt-3.5-turbo-0613");
+    }
+
+    #[test]
+    fn it_reads_the_default_file_when_it_exists() {
+        let path =
+            std::env::temp_dir().join(format!("story_teller_default_{}.toml", std::process::id()));
+        fs::write(&path, "language = \"French\"\n").unwrap();
+
+        let config = Config::load(&[], std::iter::empty(), &path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.language, "French");
+    }
+
+    #[test]
+    fn it_overrides_the_file_with_env_and_arguments() {
+        let path = std::env::temp_dir().join(format!("story_teller_{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            "endpoint = \"http://localhost:8080/v1/chat/completions\"\n\
+             functions = \"auto\"\n\
+             [chapter]\nmodel = \"gpt-4o\"\ntemperature = 1.2\n\
+             [summary]\nmodel = \"gpt-4o-mini\"\n\
+             [prices.llama3]\nprompt = 0.0\ncompletion = 0.0\n\
+             [preload]\nstrategy = { first = 2 }\n\
+             [retry]\nmax_attempts = 2\nbase_delay = 0.25\n\
+             [setup]\ngenre = \"mystery\"\n",
+        )
+        .unwrap();
+        let vars = vec![
+            (
+                String::from("STORY_TELLER__CHAPTER__MAX_TOKENS"),
+                String::from("800"),
+            ),
+            (
+                String::from("STORY_TELLER__SUMMARY__MODEL"),
+                String::from("small"),
+            ),
+            (
+                String::from("STORY_TELLER_RECORD"),
+                String::from("session.jsonl"),
+            ),
+        ];
+        let line = format!(
+            "--config {} --set chapter.temperature=0.7 --set summary.seed=3 --set budget=0.5 --set preload.max_concurrent=1 --set seed=7 --set setup.protagonist=Sam --set language=French --set retry.max_delay=10",
+            path.display()
+        );
+
+        let config = load(&args(&line), vars.into_iter()).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.endpoint, "http://localhost:8080/v1/chat/completions");
+        assert_eq!(config.functions, FunctionMode::Auto);
+        assert_eq!(config.chapter.model, "gpt-4o");
+        assert_eq!(config.chapter.temperature, Some(0.7));
+        assert_eq!(config.chapter.max_tokens, Some(800));
+        assert_eq!(config.summary.model, "small");
+        assert_eq!(config.summary.seed, Some(3));
+        assert_eq!(config.prices["llama3"], Price::new(0.0, 0.0));
+        assert_eq!(config.budget, Some(0.5));
+        assert_eq!(
+            crate::narrator::Preloader::new(&config.preload).choices(3),
+            0..2
+        );
+        assert_eq!(config.preload.max_concurrent, 1);
+        assert_eq!(config.seed, Some(7));
+        assert_eq!(config.language, "French");
+        assert_eq!(
+            config.retry,
+            RetryPolicy {
+                max_attempts: 2,
+                base_delay: Duration::from_millis(250),
+                max_delay: Duration::from_secs(10),
+            }
+        );
+        assert_eq!(
+            config.setup,
+            Some(StorySetup {
+                genre: Some(String::from("mystery")),
+                protagonist: Some(String::from("Sam")),
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn it_rejects_unknown_settings() {
+        let result = load(&args("--set chapter.temprature=1"), std::iter::empty());
+        assert!(matches!(result, Err(Error::InvalidConfig(_))));
+
+        let result = load(&args("--set retry.max_delay=-1"), std::iter::empty());
+        assert!(matches!(result, Err(Error::InvalidConfig(_))));
+    }
+}
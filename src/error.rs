@@ -0,0 +1,110 @@
+use std::fmt;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    Transport(reqwest::Error),
+    Http {
+        status: reqwest::StatusCode,
+        body: String,
+    },
+    Deserialization(serde_json::Error),
+    SchemaViolation(String),
+    MissingFunctionCall,
+    MissingJson(String),
+    EmptyResponse,
+    NotRecorded(String),
+    ExhaustedRetries {
+        attempts: u32,
+        last: Box<Error>,
+    },
+    ContextWindowExceeded {
+        tokens: u32,
+        limit: u32,
+    },
+    TaskJoin(tokio::task::JoinError),
+    Io(std::io::Error),
+    InvalidSave(String),
+    InvalidConfig(String),
+    InvalidTemplate(String),
+}
+
+impl Error {
+    // Errors caused by an unusable answer from the model, worth asking again.
+    pub fn is_invalid_response(&self) -> bool {
+        matches!(
+            self,
+            Error::Deserialization(_)
+                | Error::SchemaViolation(_)
+                | Error::MissingFunctionCall
+                | Error::MissingJson(_)
+                | Error::EmptyResponse
+        )
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Transport(error) => write!(f, "Unable to reach the API: {}", error),
+            Error::Http { status, body } => write!(f, "API responded with {}: {}", status, body),
+            Error::Deserialization(error) => write!(f, "Unexpected API response: {}", error),
+            Error::SchemaViolation(reason) => write!(f, "Invalid chapter: {}", reason),
+            Error::MissingFunctionCall => write!(f, "The model did not call the chapter function"),
+            Error::MissingJson(content) => write!(f, "Expected JSON from the model: {}", content),
+            Error::EmptyResponse => write!(f, "The model returned an empty response"),
+            Error::NotRecorded(key) => write!(f, "No recorded response for request {}", key),
+            Error::ExhaustedRetries { attempts, last } => {
+                write!(f, "Gave up after {} attempts: {}", attempts, last)
+            }
+            Error::ContextWindowExceeded { tokens, limit } => write!(
+                f,
+                "The request needs {} tokens but the model only accepts {}",
+                tokens, limit
+            ),
+            Error::TaskJoin(error) => write!(f, "Background task failed: {}", error),
+            Error::Io(error) => write!(f, "{}", error),
+            Error::InvalidSave(reason) => write!(f, "Invalid save file: {}", reason),
+            Error::InvalidConfig(reason) => write!(f, "Invalid configuration: {}", reason),
+            Error::InvalidTemplate(reason) => write!(f, "Invalid template {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Transport(error) => Some(error),
+            Error::Deserialization(error) => Some(error),
+            Error::ExhaustedRetries { last, .. } => Some(last.as_ref()),
+            Error::TaskJoin(error) => Some(error),
+            Error::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(error: reqwest::Error) -> Self {
+        Error::Transport(error)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Self {
+        Error::Deserialization(error)
+    }
+}
+
+impl From<tokio::task::JoinError> for Error {
+    fn from(error: tokio::task::JoinError) -> Self {
+        Error::TaskJoin(error)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Error::Io(error)
+    }
+}
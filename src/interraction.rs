@@ -1,20 +1,210 @@
-use crate::narrator::Story;
+use crate::chat::{metering::Ledger, ChatBackend};
+use crate::config::Models;
+use crate::error::Error;
+use crate::narrator::{ActionOutcome, Preloader, Roll, Story, StorySetup, TreeEntry, WorldState};
 use std::io::{stdout, Write};
+use std::path::{Path, PathBuf};
 use tokio::io::{self, AsyncBufReadExt, BufReader};
 
-pub async fn start(mut story: Story) {
+const DEFAULT_SAVE_FILE: &str = "story.json";
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Choose(usize),
+    Save(PathBuf),
+    Undo(usize),
+    Tree,
+    Goto(usize),
+    Act(String),
+    Cost,
+}
+
+// Prints a chapter while it is being streamed.
+#[derive(Default)]
+struct Printer {
+    // Once the separator is printed, which may be before any text.
+    started: bool,
+    text: String,
+    choices: Option<Vec<String>>,
+}
+
+pub async fn start<B: ChatBackend>(
+    backend: B,
+    models: Models,
+    ledger: Ledger,
+    preloader: Preloader,
+    setup: Option<StorySetup>,
+    seed: u64,
+    save_file: Option<PathBuf>,
+) {
+    let (mut story, mut display_chapter) = match save_file {
+        Some(path) => match Story::load(backend, models, ledger.clone(), preloader, &path) {
+            Ok(story) => (story, true),
+            Err(error) => return println!("Unable to load {}: {}", path.display(), error),
+        },
+        None => {
+            let setup = match setup {
+                Some(setup) => setup,
+                None => ask_setup().await,
+            };
+            let story = begin(backend, models, ledger.clone(), preloader, &setup, seed).await;
+            (story, false)
+        }
+    };
+
     loop {
         let (text, choices) = story.chapter();
-        display(text, choices);
-        let index = read_choice(choices.len()).await;
-        if !story.loaded(index) {
-            println!("Loading...");
+        if display_chapter {
+            display(text, choices, &story.explored(), story.state());
+        }
+        display_chapter = true;
+
+        match read_command(choices.len()).await {
+            Command::Choose(index) => {
+                if let Some(roll) = story.roll(index) {
+                    display_roll(&roll);
+                }
+                if !story.loaded(index) {
+                    println!("Loading...");
+                }
+                if let Err(error) = story.choose(index).await {
+                    display_error(&error);
+                }
+            }
+            Command::Save(path) => {
+                save(&story, &path);
+                display_chapter = false;
+                prompt();
+            }
+            Command::Undo(steps) => {
+                if story.undo(steps) == 0 {
+                    println!("Already at the beginning of the story");
+                    display_chapter = false;
+                    prompt();
+                }
+            }
+            Command::Tree => {
+                display_tree(&story.tree());
+                display_chapter = false;
+                prompt();
+            }
+            Command::Goto(id) => {
+                if !story.goto(id) {
+                    println!("There is no chapter {}", id);
+                    display_chapter = false;
+                    prompt();
+                }
+            }
+            Command::Act(action) => {
+                display_chapter = act(&mut story, &action).await;
+            }
+            Command::Cost => {
+                display_cost(&ledger);
+                display_chapter = false;
+                prompt();
+            }
+        }
+    }
+}
+
+async fn begin<B: ChatBackend>(
+    backend: B,
+    models: Models,
+    ledger: Ledger,
+    preloader: Preloader,
+    setup: &StorySetup,
+    seed: u64,
+) -> Story<B> {
+    loop {
+        println!("Loading...");
+        let mut printer = Printer::default();
+        let result = Story::streamed(
+            backend.clone(),
+            models.clone(),
+            ledger.clone(),
+            preloader.clone(),
+            setup,
+            seed,
+            |text, choices| printer.update(text, choices),
+        )
+        .await;
+
+        match result {
+            Ok(story) => {
+                let (text, choices) = story.chapter();
+                printer.finish(text, choices, &story.explored(), story.state());
+                return story;
+            }
+            Err(error) => {
+                display_error(&error);
+                print!("Press enter to retry ");
+                stdout().flush().unwrap();
+                read_line().await;
+            }
+        }
+    }
+}
+
+// Every question can be skipped to let the model decide.
+async fn ask_setup() -> StorySetup {
+    println!("Set up your story, or press enter to leave it to the narrator.");
+
+    StorySetup {
+        genre: ask("Genre (fantasy, mystery, science fiction...)").await,
+        setting: ask("Setting").await,
+        tone: ask("Tone (lighthearted, dark, epic...)").await,
+        reading_level: ask("Reading level (children, young adult, adult...)").await,
+        protagonist: ask("Name of the protagonist").await,
+        background: ask("Background of the protagonist").await,
+    }
+}
+
+async fn ask(question: &str) -> Option<String> {
+    print!("{}: ", question);
+    stdout().flush().unwrap();
+    answer(&read_line().await)
+}
+
+fn answer(line: &str) -> Option<String> {
+    Some(line.trim().to_string()).filter(|answer| !answer.is_empty())
+}
+
+// Returns whether the current chapter still has to be displayed.
+async fn act<B: ChatBackend>(story: &mut Story<B>, action: &str) -> bool {
+    println!("Loading...");
+    let mut printer = Printer::default();
+    let result = story
+        .act(action, |text, choices| printer.update(text, choices))
+        .await;
+
+    match result {
+        Ok(Some(ActionOutcome::Rejected { explanation })) => {
+            println!("\n\nThis is not possible: {}", explanation);
+            true
+        }
+        Ok(outcome) => {
+            if let Some(ActionOutcome::Reinterpreted { explanation }) = outcome {
+                println!("\n({})", explanation);
+            }
+            let (text, choices) = story.chapter();
+            printer.finish(text, choices, &story.explored(), story.state());
+            false
+        }
+        Err(error) => {
+            display_error(&error);
+            true
         }
-        story.choose(index).await;
     }
 }
 
-fn valid_choice(choice: &String, cn_choices: &usize) -> Option<usize> {
+fn save<B: ChatBackend>(story: &Story<B>, path: &Path) {
+    match story.save(path) {
+        Ok(()) => println!("Story saved to {}", path.display()),
+        Err(error) => println!("Unable to save the story: {}", error),
+    }
+}
+
+fn valid_choice(choice: &str, cn_choices: &usize) -> Option<usize> {
     let choice_num = choice.trim().parse::<usize>();
     match choice_num {
         Ok(num) if num > 0 && num <= *cn_choices => Some(num - 1),
@@ -22,6 +212,39 @@ fn valid_choice(choice: &String, cn_choices: &usize) -> Option<usize> {
     }
 }
 
+// Commands start with a slash so that any other line can be an action, such as "save the
+// princess".
+fn parse_command(line: &str, cn_choices: usize) -> Option<Command> {
+    let line = line.trim();
+    let Some(command) = line.strip_prefix('/') else {
+        return match line {
+            "" => None,
+            // Numbers only ever select one of the choices
+            _ if line.parse::<usize>().is_ok() => {
+                valid_choice(line, &cn_choices).map(Command::Choose)
+            }
+            _ => Some(Command::Act(line.to_string())),
+        };
+    };
+    let (name, argument) = command.split_once(' ').unwrap_or((command, ""));
+    let argument = argument.trim();
+
+    match name {
+        "save" if argument.is_empty() => Some(Command::Save(PathBuf::from(DEFAULT_SAVE_FILE))),
+        "save" => Some(Command::Save(PathBuf::from(argument))),
+        "undo" if argument.is_empty() => Some(Command::Undo(1)),
+        "undo" => argument
+            .parse()
+            .ok()
+            .filter(|steps| *steps > 0)
+            .map(Command::Undo),
+        "tree" if argument.is_empty() => Some(Command::Tree),
+        "goto" => argument.parse().ok().map(Command::Goto),
+        "cost" if argument.is_empty() => Some(Command::Cost),
+        _ => None,
+    }
+}
+
 async fn read_line() -> String {
     let mut line = String::new();
     let mut reader = BufReader::new(io::stdin());
@@ -29,29 +252,269 @@ async fn read_line() -> String {
     line
 }
 
-async fn read_choice(cn_choices: usize) -> usize {
+async fn read_command(cn_choices: usize) -> Command {
     loop {
-        let choice = read_line().await;
-        match valid_choice(&choice, &cn_choices) {
+        let line = read_line().await;
+        match parse_command(&line, cn_choices) {
             None => {
-                println!("Invalid choice");
+                println!("Invalid choice or command");
                 continue;
             }
-            Some(index) => {
-                return index;
+            Some(command) => {
+                return command;
             }
         }
     }
 }
 
-fn display(text: &String, choices: &Vec<String>) {
-    let mut lock = stdout().lock();
-
+fn display(text: &str, choices: &[String], explored: &[bool], state: &WorldState) {
     println!("\n-----\n{}\n", text);
+    display_choices(choices, explored);
+    display_status(state);
+    prompt();
+}
+
+fn display_choices(choices: &[String], explored: &[bool]) {
     for (i, choice) in choices.iter().enumerate() {
-        println!("  {}: {}", i + 1, choice);
+        match explored.get(i) {
+            Some(true) => println!("  {}: {} (explored)", i + 1, choice),
+            _ => println!("  {}: {}", i + 1, choice),
+        }
     }
+}
+
+fn display_roll(roll: &Roll) {
+    println!(
+        "Rolling for {}: {} + {} = {} against {}, {}!",
+        roll.stat,
+        roll.die,
+        roll.modifier,
+        roll.total(),
+        roll.difficulty,
+        roll.outcome()
+    );
+}
+
+// Nothing is displayed until the story establishes something.
+fn display_status(state: &WorldState) {
+    let lines = status(state);
+    if lines.is_empty() {
+        return;
+    }
+
+    println!("\n  +---");
+    for line in lines {
+        println!("  | {}", line);
+    }
+    println!("  +---");
+}
+
+fn status(state: &WorldState) -> Vec<String> {
+    let mut lines = Vec::new();
+    let list = |values: Vec<String>| values.join(", ");
+
+    if let Some(location) = &state.location {
+        lines.push(format!("Location: {}", location));
+    }
+    if !state.inventory.is_empty() {
+        lines.push(format!("Inventory: {}", list(state.inventory.clone())));
+    }
+    if !state.stats.is_empty() {
+        let stats = state
+            .stats
+            .iter()
+            .map(|(name, value)| format!("{}: {}", name, value));
+        lines.push(list(stats.collect()));
+    }
+    if !state.flags.is_empty() {
+        let flags = state.flags.iter().map(|(name, value)| match value {
+            serde_json::Value::Bool(true) => name.clone(),
+            serde_json::Value::String(value) => format!("{}: {}", name, value),
+            value => format!("{}: {}", name, value),
+        });
+        lines.push(format!("Flags: {}", list(flags.collect())));
+    }
+
+    lines
+}
+
+fn display_tree(entries: &[TreeEntry]) {
+    println!();
+    for entry in entries {
+        let indent = "  ".repeat(entry.depth + 1);
+        let choice = entry.choice.unwrap_or("Beginning");
+        match entry.current {
+            true => println!("{}{}: {} <- you are here", indent, entry.id, choice),
+            false => println!("{}{}: {}", indent, entry.id, choice),
+        }
+    }
+}
+
+fn display_cost(ledger: &Ledger) {
+    println!();
+    for (model, usage) in ledger.usage() {
+        let cost = match usage.cost {
+            Some(cost) => format!("${:.4}", cost),
+            None => String::from("unknown price"),
+        };
+        println!(
+            "  {}: {} requests, {} prompt and {} completion tokens, {}",
+            model, usage.requests, usage.prompt_tokens, usage.completion_tokens, cost
+        );
+    }
+    match ledger.budget() {
+        Some(budget) => println!("Total: ${:.4} of ${:.4}", ledger.cost(), budget),
+        None => println!("Total: ${:.4}", ledger.cost()),
+    }
+}
+
+fn prompt() {
+    let mut lock = stdout().lock();
 
     print!("\n> ");
     lock.flush().unwrap();
 }
+
+fn display_error(error: &Error) {
+    eprintln!("Error: {:?}", error);
+    println!("\nSomething went wrong: {}", error);
+    println!("You can try again.");
+}
+
+impl Printer {
+    fn update(&mut self, text: &str, choices: Option<&[String]>) {
+        let mut lock = stdout().lock();
+
+        if !self.started {
+            print!("\n-----\n");
+            self.started = true;
+        }
+
+        match text.strip_prefix(self.text.as_str()) {
+            Some(addition) => print!("{}", addition),
+            // The chapter is being written again from scratch
+            None => print!("\n\n-----\n{}", text),
+        }
+        self.text = text.to_string();
+
+        if let (Some(choices), None) = (choices, &self.choices) {
+            print!("\n\n");
+            display_choices(choices, &[]);
+            self.choices = Some(choices.to_vec());
+        }
+
+        lock.flush().unwrap();
+    }
+
+    // Displays whatever the stream did not show, such as a chapter that had to be requested
+    // again without streaming.
+    fn finish(&self, text: &str, choices: &[String], explored: &[bool], state: &WorldState) {
+        if self.text != text || self.choices.as_deref() != Some(choices) {
+            return display(text, choices, explored, state);
+        }
+
+        display_status(state);
+        prompt();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_choices() {
+        assert_eq!(parse_command(" 2\n", 3), Some(Command::Choose(1)));
+        assert_eq!(parse_command("0", 3), None);
+        assert_eq!(parse_command("4", 3), None);
+    }
+
+    #[test]
+    fn it_parses_save() {
+        assert_eq!(
+            parse_command("/save\n", 3),
+            Some(Command::Save(PathBuf::from(DEFAULT_SAVE_FILE)))
+        );
+        assert_eq!(
+            parse_command("/save  my story.json", 3),
+            Some(Command::Save(PathBuf::from("my story.json")))
+        );
+    }
+
+    #[test]
+    fn it_parses_undo() {
+        assert_eq!(parse_command("/undo", 3), Some(Command::Undo(1)));
+        assert_eq!(parse_command("/undo 3", 3), Some(Command::Undo(3)));
+        assert_eq!(parse_command("/undo 0", 3), None);
+        assert_eq!(parse_command("/undo twice", 3), None);
+    }
+
+    #[test]
+    fn it_parses_tree_navigation() {
+        assert_eq!(parse_command("/tree", 3), Some(Command::Tree));
+        assert_eq!(parse_command("/goto 12", 3), Some(Command::Goto(12)));
+        assert_eq!(parse_command("/goto", 3), None);
+    }
+
+    #[test]
+    fn it_lists_the_state_of_the_world() {
+        let mut state = WorldState::default();
+        assert!(status(&state).is_empty());
+
+        state.location = Some(String::from("the cave"));
+        state.inventory = vec![String::from("torch"), String::from("rope")];
+        state.stats.insert(String::from("health"), 7);
+        state
+            .flags
+            .insert(String::from("door"), serde_json::json!("open"));
+        state
+            .flags
+            .insert(String::from("met_the_witch"), serde_json::json!(true));
+
+        assert_eq!(
+            status(&state),
+            vec![
+                "Location: the cave",
+                "Inventory: torch, rope",
+                "health: 7",
+                "Flags: door: open, met_the_witch",
+            ]
+        );
+    }
+
+    #[test]
+    fn it_skips_empty_answers() {
+        assert_eq!(answer("  noir \n"), Some(String::from("noir")));
+        assert_eq!(answer(" \n"), None);
+    }
+
+    #[test]
+    fn it_parses_cost() {
+        assert_eq!(parse_command("/cost\n", 3), Some(Command::Cost));
+    }
+
+    #[test]
+    fn it_parses_actions() {
+        assert_eq!(
+            parse_command(" I search the drawer\n", 3),
+            Some(Command::Act(String::from("I search the drawer")))
+        );
+        assert_eq!(parse_command("\n", 3), None);
+    }
+
+    #[test]
+    fn it_parses_actions_starting_with_a_command() {
+        for action in [
+            "save the princess",
+            "undo the knot",
+            "goto the castle",
+            "tree house",
+        ] {
+            assert_eq!(
+                parse_command(action, 3),
+                Some(Command::Act(String::from(action)))
+            );
+        }
+        assert_eq!(parse_command("/jump", 3), None);
+    }
+}
@@ -1,11 +1,64 @@
+use chat::metering::{Ledger, Metered};
+use chat::{cassette, functions::FunctionCalling, ChatBackend};
+use config::Config;
+use narrator::{Preloader, Templates};
+use std::env;
+use std::path::PathBuf;
+
 mod chat;
+mod config;
+mod error;
 mod interraction;
 mod narrator;
 
 #[tokio::main]
 async fn main() {
-    let service = chat::Service::new();
-    let story = narrator::Story::new(service).await;
+    let args: Vec<String> = env::args().skip(1).collect();
+    let config = match Config::load(&args, env::vars(), config::DEFAULT_CONFIG_FILE) {
+        Ok(config) => config,
+        Err(error) => return println!("{}", error),
+    };
+    // Broken templates are reported before the story starts.
+    match Templates::load(config.templates.as_deref(), &config.language) {
+        Ok(templates) => templates.install(),
+        Err(error) => return println!("{}", error),
+    }
+    // A story saved with the `save` command can be resumed with `--load <path>`.
+    let save_file = config::argument(&args, "--load").map(PathBuf::from);
+
+    if let Ok(path) = env::var("STORY_TELLER_REPLAY") {
+        return match cassette::Replayer::open(&path) {
+            Ok(replayer) => play(replayer, &config, save_file).await,
+            Err(error) => println!("Unable to read the cassette {}: {}", path, error),
+        };
+    }
+
+    let api_key = config
+        .api_key
+        .clone()
+        .or_else(|| env::var("OPENAI_API_KEY").ok());
+    let service = chat::Service::new(config.endpoint.clone(), api_key, config.retry.clone());
+
+    match env::var("STORY_TELLER_RECORD") {
+        Ok(path) => match cassette::Recorder::new(service, &path) {
+            Ok(recorder) => play(recorder, &config, save_file).await,
+            Err(error) => println!("Unable to open the cassette {}: {}", path, error),
+        },
+        Err(_) => play(service, &config, save_file).await,
+    }
+}
+
+// Cassettes hold the requests as sent to the server, so function calls are adapted on top of
+// them. Every request actually sent is metered, retries and fallbacks included.
+async fn play<B: ChatBackend>(backend: B, config: &Config, save_file: Option<PathBuf>) {
+    let ledger = Ledger::new(config.prices.clone(), config.budget);
+    let backend = FunctionCalling::new(Metered::new(backend, ledger.clone()), config.functions);
+    let preloader = Preloader::new(&config.preload);
+    // Logged so that a playthrough can be reproduced with `--set seed=<seed>`.
+    let seed = config.seed.unwrap_or_else(rand::random);
+    eprintln!("Seed: {}", seed);
 
-    interraction::start(story).await;
+    let models = config.models();
+    let setup = config.setup.clone();
+    interraction::start(backend, models, ledger, preloader, setup, seed, save_file).await
 }
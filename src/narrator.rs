@@ -1,11 +1,30 @@
 use chapter::Chapter;
-use linked_messages::{LinkedMessage, SharedMessage};
-use request::Request;
-pub use story::Story;
-use summarize::{message_above_threshold, Summary};
+pub use dice::Roll;
+use linked_messages::{LinkedMessage, SharedMessage, SummaryLevel};
+use memory::Memory;
+use preload::Budget;
+pub use preload::{PreloadSettings, Preloader};
+pub use request::ActionOutcome;
+use request::{ChatResponse, Request};
+use save::SavedStory;
+pub use setup::StorySetup;
+pub use story::{Story, TreeEntry};
+use summarize::Summary;
+use templates::templates;
+pub use templates::Templates;
+pub use world::WorldState;
 
 mod chapter;
+mod dice;
 mod linked_messages;
+mod memory;
+mod partial;
+mod preload;
 mod request;
+mod save;
+mod setup;
 mod story;
 mod summarize;
+mod templates;
+mod tree;
+mod world;
@@ -1,29 +1,87 @@
-use super::{Request, SharedMessage};
-use crate::chat::{Message, Role, Service};
+use super::dice::{self, Check, Roll};
+use super::{ActionOutcome, ChatResponse, Request, SharedMessage, WorldState};
+use crate::chat::{request::ModelSettings, ChatBackend, Message, Role};
+use crate::error::Result;
 
+#[derive(Clone)]
 pub struct Chapter {
     text: String,
     message: SharedMessage,
     choices: Vec<String>,
+    // How the model took the reader's own action, if this chapter answers one.
+    action: Option<ActionOutcome>,
+    // Once the chapter happened.
+    state: WorldState,
+    // Decides the rolls of the checks, and the seeds of the chapters following this one.
+    seed: u64,
+    checks: Vec<Check>,
 }
 
 impl Chapter {
-    pub async fn load(service: &Service, parent: Option<SharedMessage>, content: String) -> Self {
-        let request = Request::new(parent.clone(), content);
-        let (parsed_response, total_tokens) = request.perform(service).await;
+    pub fn new(
+        text: String,
+        choices: Vec<String>,
+        message: SharedMessage,
+        state: WorldState,
+        seed: u64,
+        checks: Vec<Check>,
+    ) -> Self {
+        Self {
+            text,
+            message,
+            choices,
+            action: None,
+            state,
+            seed,
+            checks,
+        }
+    }
+
+    pub async fn load<B: ChatBackend>(
+        backend: &B,
+        settings: ModelSettings,
+        parent: Option<SharedMessage>,
+        content: String,
+        state: WorldState,
+        seed: u64,
+    ) -> Result<Self> {
+        let request = Request::new(settings, parent.clone(), content);
+        let response = request.perform(backend).await?;
+
+        Ok(Self::from_response(parent, state, seed, response))
+    }
+
+    pub async fn stream<B: ChatBackend>(
+        backend: &B,
+        settings: ModelSettings,
+        parent: Option<SharedMessage>,
+        content: String,
+        state: WorldState,
+        seed: u64,
+        on_progress: impl FnMut(&str, Option<&[String]>) + Send,
+    ) -> Result<Self> {
+        let request = Request::new(settings, parent.clone(), content);
+        let response = request.stream(backend, on_progress).await?;
+
+        Ok(Self::from_response(parent, state, seed, response))
+    }
+
+    // The state of the world is the one before the chapter, with its changes.
+    fn from_response(
+        parent: Option<SharedMessage>,
+        mut state: WorldState,
+        seed: u64,
+        (parsed_response, total_tokens): (ChatResponse, u32),
+    ) -> Self {
         let text = parsed_response.text.clone();
         let choices = parsed_response.choices;
+        state.apply(&parsed_response.state);
 
         // I've chosen to recreate the message because there is a bug with OpenAI API: it doesn't
         // accept messages without content attribute. It should also helps reducing the number of
         // spent tokens.
         let message = SharedMessage::new(
-            Message {
-                role: Role::Assistant,
-                content: Some(text.clone()),
-                name: None,
-                function_call: None,
-            },
+            Message::new(Role::Assistant, Some(text.clone())),
             // Linking to the parent and not the query message I discard the user choices. I
             // believe they do not provide value to the context.
             parent,
@@ -31,9 +89,8 @@ impl Chapter {
         );
 
         Self {
-            message,
-            text,
-            choices,
+            action: parsed_response.action,
+            ..Self::new(text, choices, message, state, seed, parsed_response.checks)
         }
     }
 
@@ -48,4 +105,31 @@ impl Chapter {
     pub fn message(&self) -> &SharedMessage {
         &self.message
     }
+
+    pub fn action(&self) -> Option<&ActionOutcome> {
+        self.action.as_ref()
+    }
+
+    pub fn state(&self) -> &WorldState {
+        &self.state
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn checks(&self) -> &Vec<Check> {
+        &self.checks
+    }
+
+    // Seed of the chapter following the choice, or action, at `index`.
+    pub fn next_seed(&self, index: usize) -> u64 {
+        dice::seed(self.seed, index)
+    }
+
+    // The outcome of the choice at `index`, if it is a check.
+    pub fn roll(&self, index: usize) -> Option<Roll> {
+        let check = self.checks.iter().find(|check| check.choice == index)?;
+        Some(Roll::new(self.next_seed(index), check, &self.state))
+    }
 }
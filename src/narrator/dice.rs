@@ -0,0 +1,126 @@
+use super::{templates, WorldState};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+const DIE: i64 = 20;
+
+// A choice whose outcome is decided by a roll, as marked by the model.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Check {
+    // Index of the choice.
+    pub choice: usize,
+    pub stat: String,
+    pub difficulty: i64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Roll {
+    pub stat: String,
+    pub difficulty: i64,
+    pub die: i64,
+    // The value of the stat in the state of the world, 0 if unknown.
+    pub modifier: i64,
+    pub success: bool,
+}
+
+impl Roll {
+    // The die is rolled with its own seed, so the same check always has the same outcome.
+    pub fn new(seed: u64, check: &Check, state: &WorldState) -> Self {
+        let die = StdRng::seed_from_u64(seed).gen_range(1..=DIE);
+        let modifier = state.stats.get(&check.stat).copied().unwrap_or(0);
+
+        Self {
+            stat: check.stat.clone(),
+            difficulty: check.difficulty,
+            die,
+            modifier,
+            success: die.saturating_add(modifier) >= check.difficulty,
+        }
+    }
+
+    // Stats come from the model, they may be anything.
+    pub fn total(&self) -> i64 {
+        self.die.saturating_add(self.modifier)
+    }
+
+    pub fn outcome(&self) -> &'static str {
+        match self.success {
+            true => "success",
+            false => "failure",
+        }
+    }
+
+    // Tells the model how the chapter has to go.
+    pub fn prompt(&self, player: &str) -> String {
+        templates().render(
+            "roll.txt",
+            &[
+                ("player", player),
+                ("stat", &self.stat),
+                ("difficulty", &self.difficulty.to_string()),
+                ("die", &self.die.to_string()),
+                ("modifier", &self.modifier.to_string()),
+                ("total", &self.total().to_string()),
+                ("outcome", self.outcome()),
+            ],
+        )
+    }
+}
+
+// Seed of what follows a branch, from the seed of the chapter it starts from (splitmix64).
+pub fn seed(parent: u64, branch: usize) -> u64 {
+    let mut seed = parent
+        ^ (branch as u64)
+            .wrapping_add(1)
+            .wrapping_mul(0x9E3779B97F4A7C15);
+    seed = (seed ^ (seed >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    seed = (seed ^ (seed >> 27)).wrapping_mul(0x94D049BB133111EB);
+    seed ^ (seed >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(difficulty: i64) -> Check {
+        Check {
+            choice: 0,
+            stat: String::from("strength"),
+            difficulty,
+        }
+    }
+
+    #[test]
+    fn it_rolls_the_same_for_the_same_seed() {
+        let state = WorldState::default();
+        let rolls: Vec<i64> = (0..50)
+            .map(|branch| Roll::new(seed(42, branch), &check(10), &state).die)
+            .collect();
+        let again: Vec<i64> = (0..50)
+            .map(|branch| Roll::new(seed(42, branch), &check(10), &state).die)
+            .collect();
+
+        assert_eq!(rolls, again);
+        assert!(rolls.iter().all(|die| (1..=DIE).contains(die)));
+        assert!(rolls.iter().any(|die| *die != rolls[0]));
+        assert_ne!(seed(42, 0), seed(43, 0));
+    }
+
+    #[test]
+    fn it_adds_the_stat_to_the_die() {
+        let mut state = WorldState::default();
+        state.stats.insert(String::from("strength"), 25);
+
+        let roll = Roll::new(7, &check(25), &state);
+
+        assert_eq!(roll.modifier, 25);
+        assert_eq!(roll.total(), roll.die + 25);
+        assert!(roll.success);
+        assert!(!Roll::new(7, &check(100), &state).success);
+
+        state.stats.insert(String::from("strength"), i64::MAX);
+        let roll = Roll::new(7, &check(i64::MAX), &state);
+        assert_eq!(roll.total(), i64::MAX);
+        assert!(roll.success);
+    }
+}
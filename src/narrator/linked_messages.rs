@@ -1,4 +1,5 @@
-use crate::chat::Message;
+use crate::chat::{tokens, Message};
+use serde::{Deserialize, Serialize};
 use std::sync::{Arc, RwLock};
 
 #[derive(Clone, Debug)]
@@ -8,7 +9,21 @@ pub struct SharedMessage(Arc<RwLock<LinkedMessage>>);
 pub struct LinkedMessage {
     pub message: Message,
     pub parent: Option<SharedMessage>,
+    // Reported by the API for the request which generated the message.
     pub total_tokens: Option<u32>,
+    // Set once the message has been replaced by a summary.
+    pub summary: Option<SummaryLevel>,
+    // Kept at the root of the history, such as the persona of the narrator, and never summarized.
+    pub pinned: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SummaryLevel {
+    // Chapters following the previous summary.
+    Arc,
+    // Everything up to the message, earlier summaries included.
+    Synopsis,
 }
 
 impl SharedMessage {
@@ -17,9 +32,17 @@ impl SharedMessage {
             message,
             parent,
             total_tokens,
+            summary: None,
+            pinned: false,
         })))
     }
 
+    pub fn pinned(message: Message, parent: Option<SharedMessage>) -> Self {
+        let shared = Self::new(message, parent, None);
+        shared.write().pinned = true;
+        shared
+    }
+
     pub fn read(&self) -> std::sync::RwLockReadGuard<'_, LinkedMessage> {
         self.0.read().unwrap()
     }
@@ -27,9 +50,44 @@ impl SharedMessage {
     pub fn write(&self) -> std::sync::RwLockWriteGuard<'_, LinkedMessage> {
         self.0.write().unwrap()
     }
+
+    pub fn ptr_eq(&self, other: &SharedMessage) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+
+    // Every message from the root of the history to this one.
+    pub fn chain(&self) -> Vec<SharedMessage> {
+        let mut chain = vec![self.clone()];
+
+        loop {
+            let parent = chain.last().unwrap().read().parent.clone();
+            match parent {
+                Some(parent) => chain.push(parent),
+                None => break,
+            }
+        }
+
+        chain.reverse();
+        chain
+    }
 }
 
 impl LinkedMessage {
+    // Size of the history up to this message. The count reported by the API is preferred, the
+    // messages built locally or received without usage are counted with the tokenizer.
+    pub fn tokens(&self) -> u32 {
+        match self.total_tokens {
+            Some(total_tokens) if total_tokens > 0 => total_tokens,
+            _ => {
+                let parent = self
+                    .parent
+                    .as_ref()
+                    .map_or(0, |parent| parent.read().tokens());
+                parent + tokens::count_message(&self.message)
+            }
+        }
+    }
+
     pub fn messages(&self) -> Vec<Message> {
         let mut messages: Vec<Message> = Vec::new();
         let mut linked_message = self.clone();
@@ -52,3 +110,24 @@ impl LinkedMessage {
         messages
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::Role;
+
+    #[test]
+    fn it_counts_the_tokens_of_local_messages() {
+        let message = |content: &str| Message::new(Role::User, Some(content.to_string()));
+        let root = SharedMessage::new(message("Hello, world!"), None, None);
+        let reported = SharedMessage::new(message("Once"), Some(root.clone()), Some(1000));
+        let local = SharedMessage::new(message("Hello, world!"), Some(reported), None);
+
+        assert_eq!(root.read().tokens(), 7);
+        assert_eq!(local.read().tokens(), 1007);
+
+        // Streamed replies may come without usage
+        let unreported = SharedMessage::new(message("Once"), Some(root.clone()), Some(0));
+        assert_eq!(unreported.read().tokens(), 7 + 4);
+    }
+}
@@ -0,0 +1,376 @@
+use super::{templates, SharedMessage, Summary, SummaryLevel};
+use crate::chat::request::{Body, ModelSettings, Tool};
+use crate::chat::{tokens, ChatBackend};
+use crate::error::Result;
+use tokio::task::{spawn, JoinHandle};
+
+// Room kept for the prompt of the next chapter, along with the world state.
+const PROMPT_TOKENS: u32 = 300;
+// The most recent chapters are never summarized.
+const RECENT_MESSAGES: usize = 2;
+// Beyond this, the older arcs are condensed into the synopsis.
+const MAX_ARCS: usize = 3;
+
+type Task = (SharedMessage, JoinHandle<Result<Summary>>);
+
+// Sizes of the history, in tokens, derived from the context window of the chapter model.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Thresholds {
+    // An arc is summarized in the background from then on.
+    summary: u32,
+    // The history is cut at the summary, waiting for it if needed.
+    reduce: u32,
+    // Chapters summarized together, counted locally.
+    arc: u32,
+}
+
+// The history is kept in layers: the pinned messages, a synopsis of the story, the summaries of
+// the arcs following it and the most recent chapters verbatim. Summaries replace the last message
+// they cover and link to the previous summary or to the pinned messages, so the chapters before
+// them are left out of the history but still link to their original parents.
+pub struct Memory {
+    // Summarized up to this message, once the history gets too long.
+    arc: Option<Task>,
+    // Arc summaries condensed up to this one, as soon as possible.
+    synopsis: Option<Task>,
+    thresholds: Thresholds,
+}
+
+impl Memory {
+    pub fn new(chapter: &ModelSettings) -> Self {
+        Self {
+            arc: None,
+            synopsis: None,
+            thresholds: Thresholds::new(chapter),
+        }
+    }
+
+    pub fn arc(&self) -> Option<&SharedMessage> {
+        self.arc.as_ref().map(|(message, _)| message)
+    }
+
+    pub fn synopsis(&self) -> Option<&SharedMessage> {
+        self.synopsis.as_ref().map(|(message, _)| message)
+    }
+
+    pub fn summarize<B: ChatBackend>(
+        &mut self,
+        backend: &B,
+        settings: &ModelSettings,
+        message: SharedMessage,
+        level: SummaryLevel,
+    ) {
+        let summary = Summary::new(backend.clone(), settings.clone(), message.clone(), level);
+        let task = Some((message, spawn(summary)));
+
+        match level {
+            SummaryLevel::Arc => self.arc = task,
+            SummaryLevel::Synopsis => self.synopsis = task,
+        }
+    }
+
+    // Called with the history of every chapter the reader reaches. A failed summary is dropped,
+    // the next chapter will start a new one.
+    pub async fn update<B: ChatBackend>(
+        &mut self,
+        backend: &B,
+        settings: &ModelSettings,
+        message: &SharedMessage,
+    ) {
+        if self
+            .synopsis
+            .as_ref()
+            .is_some_and(|(_, task)| task.is_finished())
+        {
+            let (_, task) = self.synopsis.take().unwrap();
+            finish(task).await;
+        }
+
+        let tokens = message.read().tokens();
+        if self.arc.is_none() && tokens >= self.thresholds.summary {
+            if let Some(end) = arc_end(message, self.thresholds.arc) {
+                self.summarize(backend, settings, end, SummaryLevel::Arc);
+            }
+        }
+        if self.synopsis.is_none() {
+            if let Some(last) = arcs_to_condense(message) {
+                self.summarize(backend, settings, last, SummaryLevel::Synopsis);
+            }
+        }
+
+        if tokens > self.thresholds.reduce {
+            self.reduce().await;
+        }
+    }
+
+    // Summaries of messages that are no longer part of the history would cut it at the wrong
+    // place.
+    pub fn discard_unrelated(&mut self, message: &SharedMessage) {
+        let chain = message.chain();
+        let related = |task: &Option<Task>| match task {
+            Some((message, _)) => chain.iter().any(|shared| shared.ptr_eq(message)),
+            None => true,
+        };
+
+        for task in [&mut self.arc, &mut self.synopsis] {
+            if !related(task) {
+                let (_, join_handle) = task.take().unwrap();
+                join_handle.abort();
+            }
+        }
+    }
+
+    async fn reduce(&mut self) {
+        if let Some((_, join_handle)) = self.arc.take() {
+            finish(join_handle).await;
+        }
+    }
+}
+
+impl Thresholds {
+    // The history has to leave room for the chapter function, the next prompt and the reply. It
+    // is reduced to fit and summarized ahead of time, by arcs of a few chapters.
+    fn new(chapter: &ModelSettings) -> Self {
+        let function = templates().chapter_function().clone();
+        let definitions = Body {
+            settings: chapter.clone(),
+            tools: Some(vec![Tool::function(function)]),
+            ..Default::default()
+        };
+        let reserved =
+            tokens::count_prompt(&definitions) + tokens::reply_tokens(chapter) + PROMPT_TOKENS;
+        let reduce = tokens::context_window(chapter).saturating_sub(reserved);
+
+        Self {
+            summary: reduce / 3,
+            reduce,
+            arc: reduce / 3,
+        }
+    }
+}
+
+// The history is left as it is when the summary could not be made.
+async fn finish(task: JoinHandle<Result<Summary>>) {
+    match task.await {
+        Ok(Ok(summary)) => apply(summary),
+        Ok(Err(error)) => eprintln!("Unable to summarize the history: {}", error),
+        Err(error) => eprintln!("Summary task failed: {}", error),
+    }
+}
+
+fn apply(summary: Summary) {
+    let parent = anchor(&summary.message, summary.level);
+    let mut message = summary.message.write();
+
+    message.parent = parent;
+    message.message.content = Some(summary.content);
+    message.summary = Some(summary.level);
+}
+
+// Arcs follow the previous summary, the synopsis covers every summary. Both follow the pinned
+// messages.
+fn anchor(message: &SharedMessage, level: SummaryLevel) -> Option<SharedMessage> {
+    let chain = message.chain();
+    chain[..chain.len() - 1]
+        .iter()
+        .rfind(|shared| {
+            let linked_message = shared.read();
+            linked_message.pinned
+                || (level == SummaryLevel::Arc && linked_message.summary.is_some())
+        })
+        .cloned()
+}
+
+// The arc starts after the latest summary or pinned message and stops once it is long enough, or
+// before the most recent messages.
+fn arc_end(message: &SharedMessage, arc_tokens: u32) -> Option<SharedMessage> {
+    let chain = message.chain();
+    let start = chain
+        .iter()
+        .rposition(|shared| {
+            let linked_message = shared.read();
+            linked_message.pinned || linked_message.summary.is_some()
+        })
+        .map_or(0, |index| index + 1);
+    let end = chain.len().saturating_sub(RECENT_MESSAGES).max(start);
+    let mut size = 0;
+
+    for shared in &chain[start..end] {
+        size += tokens::count_message(&shared.read().message);
+        if size >= arc_tokens {
+            return Some(shared.clone());
+        }
+    }
+
+    chain[start..end].last().cloned()
+}
+
+// Every arc but the latest is condensed, along with the previous synopsis.
+fn arcs_to_condense(message: &SharedMessage) -> Option<SharedMessage> {
+    let arcs: Vec<SharedMessage> = message
+        .chain()
+        .into_iter()
+        .filter(|shared| shared.read().summary == Some(SummaryLevel::Arc))
+        .collect();
+
+    match arcs.len() > MAX_ARCS {
+        true => arcs.get(arcs.len() - 2).cloned(),
+        false => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::mock::{summary_response, ScriptedBackend};
+    use crate::chat::{Message, Role};
+    use crate::error::Error;
+
+    const ARC_TOKENS: u32 = 1000;
+
+    fn history(contents: &[&str]) -> Vec<SharedMessage> {
+        let mut chain: Vec<SharedMessage> = Vec::new();
+
+        for content in contents {
+            let message = Message::new(Role::Assistant, Some(content.to_string()));
+            chain.push(SharedMessage::new(message, chain.last().cloned(), None));
+        }
+
+        chain
+    }
+
+    fn summarized(message: &SharedMessage, level: SummaryLevel, content: &str) {
+        apply(Summary {
+            message: message.clone(),
+            level,
+            content: content.to_string(),
+        });
+    }
+
+    fn contents(message: &SharedMessage) -> Vec<String> {
+        let messages = message.read().messages();
+        messages.into_iter().filter_map(|m| m.content).collect()
+    }
+
+    #[test]
+    fn it_keeps_the_recent_messages_out_of_the_arc() {
+        let chain = history(&["one", "two", "three", "four"]);
+        assert!(arc_end(&chain[3], ARC_TOKENS).unwrap().ptr_eq(&chain[1]));
+
+        summarized(&chain[1], SummaryLevel::Arc, "arc");
+        assert!(arc_end(&chain[3], ARC_TOKENS).is_none());
+    }
+
+    #[test]
+    fn it_stops_the_arc_once_long_enough() {
+        let long = "word ".repeat(600);
+        let chain = history(&[&long, &long, "three", "four", "five"]);
+
+        assert!(arc_end(&chain[4], ARC_TOKENS).unwrap().ptr_eq(&chain[1]));
+    }
+
+    #[test]
+    fn it_links_arcs_to_the_previous_summary() {
+        let chain = history(&["one", "two", "three", "four", "five"]);
+        summarized(&chain[0], SummaryLevel::Arc, "first arc");
+        summarized(&chain[2], SummaryLevel::Arc, "second arc");
+
+        assert_eq!(
+            contents(&chain[4]),
+            vec!["first arc", "second arc", "four", "five"]
+        );
+        // Chapters before the second summary still follow the first one
+        assert_eq!(contents(&chain[1]), vec!["first arc", "two"]);
+    }
+
+    #[test]
+    fn it_condenses_the_older_arcs() {
+        let chain = history(&["one", "two", "three", "four", "five", "six"]);
+        for (index, message) in chain[..4].iter().enumerate() {
+            summarized(message, SummaryLevel::Arc, &format!("arc {}", index));
+        }
+        assert!(arcs_to_condense(&chain[2]).is_none());
+
+        let last = arcs_to_condense(&chain[5]).unwrap();
+        assert!(last.ptr_eq(&chain[2]));
+        summarized(&last, SummaryLevel::Synopsis, "synopsis");
+
+        assert_eq!(
+            contents(&chain[5]),
+            vec!["synopsis", "arc 3", "five", "six"]
+        );
+        assert!(arcs_to_condense(&chain[5]).is_none());
+    }
+
+    #[tokio::test]
+    async fn it_drops_failed_summaries_and_starts_again() {
+        let backend = ScriptedBackend::new(|_| Err(Error::NotRecorded(String::from("summary"))));
+        let chain = history(&["one", "two", "three"]);
+        let message = Message::new(Role::Assistant, Some(String::from("four")));
+        let last = SharedMessage::new(message, Some(chain[2].clone()), Some(4000));
+        let mut memory = Memory::new(&Default::default());
+
+        memory.update(&backend, &Default::default(), &last).await;
+        assert!(memory.arc().is_none());
+        assert_eq!(contents(&last), vec!["one", "two", "three", "four"]);
+
+        memory.update(&backend, &Default::default(), &last).await;
+        assert_eq!(backend.requests().len(), 2);
+    }
+
+    #[test]
+    fn it_sizes_the_history_after_the_context_window() {
+        let settings = |model: &str, context_window| ModelSettings {
+            model: model.to_string(),
+            context_window,
+            ..Default::default()
+        };
+        let small = Thresholds::new(&settings("llama3", Some(2000)));
+        let default = Thresholds::new(&Default::default());
+        let large = Thresholds::new(&settings("gpt-4o", None));
+
+        assert!(small.reduce < 2000 && small.summary < small.reduce);
+        assert!(default.reduce < 4096 && small.reduce < default.reduce);
+        assert!(large.reduce > 100_000);
+    }
+
+    #[tokio::test]
+    async fn it_reduces_the_history_to_fit_a_small_context_window() {
+        let backend = ScriptedBackend::new(|_| Ok(summary_response("SUMMARY", 10)));
+        let paragraph = "word ".repeat(150);
+        let chain = history(&[paragraph.as_str(); 8]);
+        // Streamed without usage
+        let message = Message::new(Role::Assistant, Some(String::from("last")));
+        let last = SharedMessage::new(message, Some(chain[7].clone()), Some(0));
+        let settings = ModelSettings {
+            context_window: Some(2000),
+            ..Default::default()
+        };
+
+        Memory::new(&Default::default())
+            .update(&backend, &settings, &last)
+            .await;
+        assert!(backend.requests().is_empty());
+
+        Memory::new(&settings)
+            .update(&backend, &settings, &last)
+            .await;
+        assert_eq!(contents(&last)[0], "SUMMARY");
+    }
+
+    #[test]
+    fn it_keeps_the_pinned_messages_ahead_of_summaries() {
+        let chain = history(&["persona", "one", "two", "three", "four"]);
+        chain[0].write().pinned = true;
+        assert!(arc_end(&chain[4], ARC_TOKENS).unwrap().ptr_eq(&chain[2]));
+
+        summarized(&chain[2], SummaryLevel::Arc, "arc");
+        assert_eq!(contents(&chain[4]), vec!["persona", "arc", "three", "four"]);
+
+        summarized(&chain[2], SummaryLevel::Synopsis, "synopsis");
+        assert_eq!(
+            contents(&chain[4]),
+            vec!["persona", "synopsis", "three", "four"]
+        );
+    }
+}
@@ -0,0 +1,173 @@
+// Reads fields out of the chapter arguments while they are still being streamed, before the
+// JSON document is complete.
+
+// Decoded value of a top-level string field, possibly truncated.
+pub fn string_field(json: &str, key: &str) -> Option<String> {
+    let value = &json[value_start(json, key)?..];
+    let mut chars = value.strip_prefix('"')?.chars();
+    let mut decoded = String::new();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => break,
+            '\\' => match chars.next() {
+                Some('n') => decoded.push('\n'),
+                Some('t') => decoded.push('\t'),
+                Some('r') => decoded.push('\r'),
+                Some('b') => decoded.push('\u{8}'),
+                Some('f') => decoded.push('\u{c}'),
+                Some('u') => {
+                    let code: String = chars.by_ref().take(4).collect();
+                    match u32::from_str_radix(&code, 16).ok().and_then(char::from_u32) {
+                        Some(c) if code.len() == 4 => decoded.push(c),
+                        // Truncated escape or half of a surrogate pair
+                        _ => break,
+                    }
+                }
+                Some(c) => decoded.push(c),
+                None => break,
+            },
+            c => decoded.push(c),
+        }
+    }
+
+    Some(decoded)
+}
+
+// Value of a top-level array field, once its closing bracket has been received.
+pub fn complete_array<T: serde::de::DeserializeOwned>(json: &str, key: &str) -> Option<T> {
+    let value = &json[value_start(json, key)?..];
+
+    if !value.starts_with('[') {
+        return None;
+    }
+
+    let mut depth = 0;
+    let mut position = 0;
+    while position < value.len() {
+        match value.as_bytes()[position] {
+            b'"' => {
+                position = string_end(value, position)?;
+                continue;
+            }
+            b'[' | b'{' => depth += 1,
+            b']' | b'}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return serde_json::from_str(&value[..=position]).ok();
+                }
+            }
+            _ => (),
+        }
+        position += 1;
+    }
+
+    None
+}
+
+// Position of the value associated with a key of the top-level object.
+fn value_start(json: &str, key: &str) -> Option<usize> {
+    let bytes = json.as_bytes();
+    let mut depth = 0;
+    let mut expecting_key = false;
+    let mut position = 0;
+
+    while position < bytes.len() {
+        match bytes[position] {
+            b'"' => {
+                let end = string_end(json, position)?;
+
+                if depth == 1 && expecting_key {
+                    expecting_key = false;
+                    let after_key = &json[end..];
+                    let colon = after_key.find(':')?;
+                    let value = end + colon + 1;
+                    let value = value + (json[value..].len() - json[value..].trim_start().len());
+
+                    if &json[position + 1..end - 1] == key {
+                        return (value < json.len()).then_some(value);
+                    }
+                    position = value;
+                } else {
+                    position = end;
+                }
+                continue;
+            }
+            b'{' => {
+                depth += 1;
+                expecting_key = depth == 1;
+            }
+            b'[' => depth += 1,
+            b'}' | b']' => depth -= 1,
+            b',' if depth == 1 => expecting_key = true,
+            _ => (),
+        }
+        position += 1;
+    }
+
+    None
+}
+
+// Position right after the closing quote of the string starting at `start`.
+fn string_end(json: &str, start: usize) -> Option<usize> {
+    let bytes = json.as_bytes();
+    let mut position = start + 1;
+
+    while position < bytes.len() {
+        match bytes[position] {
+            b'\\' => position += 2,
+            b'"' => return Some(position + 1),
+            _ => position += 1,
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ARGUMENTS: &str =
+        r#"{"text": "Once upon a \"time\"\nthe end", "choices": ["a [b]", "c"]}"#;
+
+    #[test]
+    fn it_reads_a_truncated_string() {
+        let partial = &ARGUMENTS[..24];
+        assert_eq!(
+            string_field(partial, "text").as_deref(),
+            Some("Once upon a \"")
+        );
+    }
+
+    #[test]
+    fn it_reads_a_complete_string() {
+        assert_eq!(
+            string_field(ARGUMENTS, "text").as_deref(),
+            Some("Once upon a \"time\"\nthe end")
+        );
+    }
+
+    #[test]
+    fn it_waits_for_the_value() {
+        assert_eq!(string_field(r#"{"text"#, "text"), None);
+        assert_eq!(string_field(r#"{"text":"#, "text"), None);
+        assert_eq!(string_field(r#"{"text": ""#, "text").as_deref(), Some(""));
+    }
+
+    #[test]
+    fn it_ignores_keys_inside_values() {
+        let json = r#"{"choices": ["text"], "other": {"text": "no"}, "text": "yes"}"#;
+        assert_eq!(string_field(json, "text").as_deref(), Some("yes"));
+    }
+
+    #[test]
+    fn it_reads_arrays_once_closed() {
+        let open = &ARGUMENTS[..ARGUMENTS.len() - 2];
+        assert_eq!(complete_array::<Vec<String>>(open, "choices"), None);
+        assert_eq!(
+            complete_array::<Vec<String>>(ARGUMENTS, "choices"),
+            Some(vec![String::from("a [b]"), String::from("c")])
+        );
+    }
+}
@@ -0,0 +1,168 @@
+use crate::error::Result;
+use serde::Deserialize;
+use std::future::Future;
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::task::{spawn, JoinHandle};
+
+// Which choices of a chapter are requested before the reader picks one.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PreloadStrategy {
+    #[default]
+    All,
+    None,
+    First(usize),
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PreloadSettings {
+    pub strategy: PreloadStrategy,
+    // Preloads waiting for a slot are aborted as soon as the reader moves on.
+    pub max_concurrent: usize,
+    // 2 also preloads the choices of the preloaded chapters, as soon as they are received.
+    pub depth: usize,
+    // Preloads started from a chapter, at any depth. Unlimited by default.
+    pub max_requests: Option<usize>,
+}
+
+// Starts the preloads of a story, never more than `max_concurrent` requests at a time.
+#[derive(Clone)]
+pub struct Preloader {
+    strategy: PreloadStrategy,
+    semaphore: Arc<Semaphore>,
+    depth: usize,
+    max_requests: Option<usize>,
+}
+
+// Requests left to preload from a chapter, shared by every depth.
+#[derive(Clone, Debug)]
+pub struct Budget(Arc<AtomicUsize>);
+
+impl Default for PreloadSettings {
+    fn default() -> Self {
+        Self {
+            strategy: PreloadStrategy::default(),
+            max_concurrent: 4,
+            depth: 1,
+            max_requests: None,
+        }
+    }
+}
+
+impl Preloader {
+    pub fn new(settings: &PreloadSettings) -> Self {
+        Self {
+            strategy: settings.strategy,
+            semaphore: Arc::new(Semaphore::new(settings.max_concurrent.max(1))),
+            depth: settings.depth,
+            max_requests: settings.max_requests,
+        }
+    }
+
+    // Whether the choices of preloaded chapters are preloaded as well.
+    pub fn lookahead(&self) -> bool {
+        self.depth > 1
+    }
+
+    pub fn budget(&self) -> Budget {
+        Budget(Arc::new(AtomicUsize::new(
+            self.max_requests.unwrap_or(usize::MAX),
+        )))
+    }
+
+    // Indices of the choices to preload.
+    pub fn choices(&self, count: usize) -> Range<usize> {
+        match self.strategy {
+            PreloadStrategy::All => 0..count,
+            PreloadStrategy::None => 0..0,
+            PreloadStrategy::First(first) => 0..first.min(count),
+        }
+    }
+
+    pub fn spawn<T: Send + 'static>(
+        &self,
+        task: impl Future<Output = Result<T>> + Send + 'static,
+    ) -> JoinHandle<Result<T>> {
+        let semaphore = self.semaphore.clone();
+
+        spawn(async move {
+            // The semaphore is never closed.
+            let _permit = semaphore.acquire_owned().await.unwrap();
+            task.await
+        })
+    }
+}
+
+impl Budget {
+    // Returns false once the budget is spent.
+    pub fn take(&self) -> bool {
+        self.0
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| {
+                left.checked_sub(1)
+            })
+            .is_ok()
+    }
+}
+
+impl Default for Preloader {
+    fn default() -> Self {
+        Self::new(&PreloadSettings::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_selects_the_choices_to_preload() {
+        let preloader = |strategy| {
+            Preloader::new(&PreloadSettings {
+                strategy,
+                ..Default::default()
+            })
+        };
+
+        assert_eq!(preloader(PreloadStrategy::All).choices(3), 0..3);
+        assert_eq!(preloader(PreloadStrategy::None).choices(3), 0..0);
+        assert_eq!(preloader(PreloadStrategy::First(2)).choices(3), 0..2);
+        assert_eq!(preloader(PreloadStrategy::First(5)).choices(3), 0..3);
+    }
+
+    #[test]
+    fn it_spends_the_budget_once() {
+        let preloader = Preloader::new(&PreloadSettings {
+            max_requests: Some(2),
+            ..Default::default()
+        });
+        let budget = preloader.budget();
+        let shared = budget.clone();
+
+        assert!(budget.take());
+        assert!(shared.take());
+        assert!(!budget.take());
+        assert!(preloader.budget().take());
+    }
+
+    #[tokio::test]
+    async fn it_waits_for_a_free_slot() {
+        let preloader = Preloader::new(&PreloadSettings {
+            max_concurrent: 1,
+            ..Default::default()
+        });
+        let permit = preloader.semaphore.clone().acquire_owned().await.unwrap();
+
+        let join_handle = preloader.spawn(async { Ok(42) });
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        assert!(!join_handle.is_finished());
+
+        drop(permit);
+        assert_eq!(join_handle.await.unwrap().unwrap(), 42);
+    }
+}
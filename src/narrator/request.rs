@@ -1,10 +1,13 @@
-use super::{LinkedMessage, SharedMessage};
-use crate::chat::{request, Message, Role, Service};
+use super::{dice::Check, partial, templates, world::StateDelta, LinkedMessage, SharedMessage};
+use crate::chat::{request, ChatBackend, Message, Role};
+use crate::error::{Error, Result};
 use serde::{self, Deserialize};
+use serde_json::Value;
 
 static MAX_ATTEMPTS: u32 = 3;
 
 pub struct Request {
+    settings: request::ModelSettings,
     message: LinkedMessage,
 }
 
@@ -12,85 +15,363 @@ pub struct Request {
 pub struct ChatResponse {
     pub text: String,
     pub choices: Vec<String>,
+    // Only expected when the reader wrote their own action.
+    #[serde(default)]
+    pub action: Option<ActionOutcome>,
+    #[serde(default)]
+    pub checks: Vec<Check>,
+    #[serde(default)]
+    pub state: StateDelta,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum ActionOutcome {
+    Accepted,
+    Reinterpreted {
+        #[serde(default)]
+        explanation: String,
+    },
+    Rejected {
+        #[serde(default)]
+        explanation: String,
+    },
 }
 
 impl Request {
-    pub fn new(parent: Option<SharedMessage>, content: String) -> Self {
+    pub fn new(
+        settings: request::ModelSettings,
+        parent: Option<SharedMessage>,
+        content: String,
+    ) -> Self {
         Self {
+            settings,
             message: LinkedMessage {
-                message: Message {
-                    role: Role::User,
-                    content: Some(content),
-                    name: None,
-                    function_call: None,
-                },
+                message: Message::new(Role::User, Some(content)),
                 parent,
                 total_tokens: None,
+                summary: None,
+                pinned: false,
             },
         }
     }
 
-    pub async fn perform(&self, service: &Service) -> (ChatResponse, u32) {
-        let mut attempts = 0;
-
-        while attempts < MAX_ATTEMPTS {
-            match self.perform_once(service).await {
-                Ok(value) => return value,
-                Err(error) => eprintln!("Error: {}", error),
-            }
+    pub async fn perform<B: ChatBackend>(&self, backend: &B) -> Result<(ChatResponse, u32)> {
+        self.retry(backend, Vec::new(), 0).await
+    }
 
+    // Invalid responses are sent back, followed by a message telling the model what was wrong,
+    // so the next attempt can fix it.
+    async fn retry<B: ChatBackend>(
+        &self,
+        backend: &B,
+        mut correction: Vec<Message>,
+        mut attempts: u32,
+    ) -> Result<(ChatResponse, u32)> {
+        loop {
             attempts += 1;
+
+            let (error, rejected) = match self.perform_once(backend, &correction).await {
+                Ok(value) => return Ok(value),
+                Err(rejection) => rejection,
+            };
+
+            match error {
+                error if error.is_invalid_response() && attempts < MAX_ATTEMPTS => {
+                    eprintln!("Error: {}", error);
+                    correction = correction_messages(rejected, &error);
+                }
+                error if error.is_invalid_response() => {
+                    return Err(Error::ExhaustedRetries {
+                        attempts,
+                        last: Box::new(error),
+                    })
+                }
+                error => return Err(error),
+            }
         }
+    }
 
-        panic!("Max attempts reached");
+    // Streams the chapter, reporting the text and then the choices as soon as they are known.
+    // Invalid responses are corrected without streaming.
+    pub async fn stream<B: ChatBackend>(
+        &self,
+        backend: &B,
+        mut on_progress: impl FnMut(&str, Option<&[String]>) + Send,
+    ) -> Result<(ChatResponse, u32)> {
+        let mut arguments = String::new();
+        let body = body(&self.settings, self.messages(&[]));
+        let api_response = backend
+            .stream(body, |delta| {
+                if let Some(chunk) = delta.arguments() {
+                    arguments.push_str(chunk);
+                    if let Some(text) = partial::string_field(&arguments, "text") {
+                        let choices: Option<Vec<String>> =
+                            partial::complete_array(&arguments, "choices");
+                        on_progress(&text, choices.as_deref());
+                    }
+                }
+            })
+            .await?;
+
+        let total_tokens = api_response.usage.total_tokens;
+        let message = api_response.message()?;
+        match parse_response(&message) {
+            Ok(value) => Ok((value, total_tokens)),
+            Err(error) if error.is_invalid_response() => {
+                eprintln!("Error: {}", error);
+                let correction = correction_messages(Some(message), &error);
+                self.retry(backend, correction, 1).await
+            }
+            Err(error) => Err(error),
+        }
     }
 
-    async fn perform_once(&self, service: &Service) -> Result<(ChatResponse, u32), String> {
-        let (response, total_tokens) = submit(service, &self.message).await;
+    // A response that could not be used is returned along with the error.
+    async fn perform_once<B: ChatBackend>(
+        &self,
+        backend: &B,
+        correction: &[Message],
+    ) -> std::result::Result<(ChatResponse, u32), (Error, Option<Message>)> {
+        let messages = self.messages(correction);
+        let (response, total_tokens) = submit(backend, &self.settings, messages)
+            .await
+            .map_err(|error| (error, None))?;
 
         match parse_response(&response) {
             Ok(value) => Ok((value, total_tokens)),
-            Err(error) => Err(error.to_string()),
+            Err(error) => Err((error, Some(response))),
         }
     }
+
+    fn messages(&self, correction: &[Message]) -> Vec<Message> {
+        let mut messages = self.message.messages();
+        messages.extend_from_slice(correction);
+        messages
+    }
+}
+
+// The schema is compiled once, along with the templates.
+fn function() -> &'static request::Function {
+    templates().chapter_function()
 }
 
-fn parameters() -> serde_json::Value {
-    serde_json::from_str(include_str!("parameters_schema.json")).unwrap()
+fn tools() -> Vec<request::Tool> {
+    vec![request::Tool::function(function().clone())]
 }
 
-fn functions() -> Vec<request::Function> {
-    let function =
-        request::Function::new("chapter", None, Some(parameters())).expect("Invalid JSON schema");
+// The rejected calls are answered with the errors, other replies by the user. Empty replies are
+// left out.
+fn correction_messages(rejected: Option<Message>, error: &Error) -> Vec<Message> {
+    let content = templates().render("correction.txt", &[("error", &error.to_string())]);
 
-    vec![function]
+    match rejected {
+        Some(message)
+            if message
+                .tool_calls
+                .as_ref()
+                .is_some_and(|calls| !calls.is_empty()) =>
+        {
+            let answers: Vec<Message> = message
+                .tool_calls
+                .iter()
+                .flatten()
+                .map(|call| Message::tool_result(&call.id, content.clone()))
+                .collect();
+            [vec![message], answers].concat()
+        }
+        Some(message) if message.content.is_some() => {
+            vec![message, Message::new(Role::User, Some(content))]
+        }
+        _ => vec![Message::new(Role::User, Some(content))],
+    }
 }
 
-fn body(messages: Vec<Message>) -> request::Body {
+fn body(settings: &request::ModelSettings, messages: Vec<Message>) -> request::Body {
     request::Body {
+        settings: settings.clone(),
         messages,
-        functions: Some(functions()),
-        function_call: Some(request::FunctionCall::Name("chapter")),
+        tools: Some(tools()),
+        tool_choice: Some(request::ToolChoice::Function("chapter")),
         ..Default::default()
     }
 }
 
-async fn submit(service: &Service, linked_message: &LinkedMessage) -> (Message, u32) {
-    let body = body(linked_message.messages());
-    let api_response = service.submit(body).await;
-    let response_message = api_response.choices.get(0).unwrap().message.clone();
+async fn submit<B: ChatBackend>(
+    backend: &B,
+    settings: &request::ModelSettings,
+    messages: Vec<Message>,
+) -> Result<(Message, u32)> {
+    let body = body(settings, messages);
+    let api_response = backend.submit(body).await?;
+    let response_message = api_response.message()?;
     let total_tokens = api_response.usage.total_tokens;
 
     eprintln!("Total tokens: {}", total_tokens);
-    (response_message, total_tokens)
+    Ok((response_message, total_tokens))
 }
 
-fn parse_response(message: &Message) -> serde_json::Result<ChatResponse> {
-    let function_call = message.function_call.as_ref().expect("No function call");
+fn parse_response(message: &Message) -> Result<ChatResponse> {
+    let function_call = message
+        .tool_call("chapter")
+        .ok_or(Error::MissingFunctionCall)?;
+
+    let arguments: Value = serde_json::from_str(&function_call.arguments)
+        .map_err(|error| Error::SchemaViolation(error.to_string()))?;
+    function().validate(&arguments)?;
 
-    if function_call.name != "chapter" {
-        panic!("Expected a chapter function call");
+    serde_json::from_value(arguments).map_err(|error| Error::SchemaViolation(error.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::mock::{
+        chapter_response, function_response, summary_response, ScriptedBackend,
+    };
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn it_retries_invalid_responses() {
+        let backend = ScriptedBackend::new(|body| {
+            let attempt = body.messages.len();
+            Ok(summary_response("not a chapter", attempt as u32))
+        });
+        let request = Request::new(Default::default(), None, String::from("Hello"));
+
+        let result = request.perform(&backend).await;
+
+        assert!(matches!(
+            result,
+            Err(Error::ExhaustedRetries { attempts: 3, .. })
+        ));
+        assert_eq!(backend.requests().len(), 3);
     }
 
-    serde_json::from_str(&function_call.arguments)
+    #[tokio::test]
+    async fn it_tells_the_model_what_was_wrong() {
+        let backend = ScriptedBackend::new(|body| match body.messages.len() {
+            1 => Ok(chapter_response("", &["only one"], 10)),
+            _ => Ok(chapter_response("Once", &["a", "b"], 10)),
+        });
+        let request = Request::new(Default::default(), None, String::from("Hello"));
+
+        let (response, _) = request.perform(&backend).await.unwrap();
+
+        assert_eq!(response.text, "Once");
+        let requests = backend.requests();
+        assert_eq!(requests.len(), 2);
+        let messages = &requests[1].messages;
+        assert_eq!(messages.len(), 3);
+        let rejected = messages[1].tool_call("chapter").unwrap();
+        assert!(rejected.arguments.contains("only one"));
+        assert_eq!(messages[2].role, Role::Tool);
+        assert_eq!(messages[2].tool_call_id.as_deref(), Some("call_chapter"));
+        let correction = messages[2].content.clone().unwrap();
+        assert!(correction.contains("/text"));
+        assert!(correction.contains("/choices"));
+    }
+
+    #[tokio::test]
+    async fn it_sends_back_a_reply_without_a_call() {
+        let backend = ScriptedBackend::new(|body| match body.messages.len() {
+            1 => Ok(summary_response("not a chapter", 10)),
+            _ => Ok(chapter_response("Once", &["a", "b"], 10)),
+        });
+        let request = Request::new(Default::default(), None, String::from("Hello"));
+
+        request.perform(&backend).await.unwrap();
+
+        let messages = &backend.requests()[1].messages;
+        assert_eq!(messages[1].role, Role::Assistant);
+        assert!(messages[1]
+            .content
+            .as_ref()
+            .unwrap()
+            .contains("not a chapter"));
+        assert_eq!(messages[2].role, Role::User);
+    }
+
+    #[tokio::test]
+    async fn it_corrects_an_invalid_streamed_chapter() {
+        let backend = ScriptedBackend::new(|body| match body.messages.len() {
+            1 => Ok(chapter_response("Once", &["a", "b", "c", "d", "e"], 10)),
+            _ => Ok(chapter_response("Once", &["a", "b"], 10)),
+        });
+        let request = Request::new(Default::default(), None, String::from("Hello"));
+
+        let (response, _) = request.stream(&backend, |_, _| ()).await.unwrap();
+
+        assert_eq!(response.choices, vec!["a", "b"]);
+        assert_eq!(backend.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn it_does_not_retry_other_errors() {
+        let backend = ScriptedBackend::new(|_| Err(Error::NotRecorded(String::from("key"))));
+        let request = Request::new(Default::default(), None, String::from("Hello"));
+
+        let result = request.perform(&backend).await;
+
+        assert!(matches!(result, Err(Error::NotRecorded(_))));
+        assert_eq!(backend.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn it_parses_the_chapter_function_call() {
+        let backend = ScriptedBackend::new(|_| Ok(chapter_response("Once", &["a", "b"], 42)));
+        let request = Request::new(Default::default(), None, String::from("Hello"));
+
+        let (response, total_tokens) = request.perform(&backend).await.unwrap();
+
+        assert_eq!(response.text, "Once");
+        assert_eq!(response.choices, vec!["a", "b"]);
+        assert_eq!(response.action, None);
+        assert_eq!(total_tokens, 42);
+    }
+
+    #[tokio::test]
+    async fn it_parses_the_action_outcome() {
+        let backend = ScriptedBackend::new(|_| {
+            let arguments = json!({
+                "action": { "status": "rejected", "explanation": "The door is locked" },
+                "text": "Nothing happens",
+                "choices": ["a", "b"],
+            });
+            Ok(function_response(arguments, 42))
+        });
+        let request = Request::new(Default::default(), None, String::from("I open the door"));
+
+        let (response, _) = request.perform(&backend).await.unwrap();
+
+        assert_eq!(
+            response.action,
+            Some(ActionOutcome::Rejected {
+                explanation: String::from("The door is locked")
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn it_reports_streaming_progress() {
+        let backend = ScriptedBackend::new(|_| Ok(chapter_response("Once", &["a", "b"], 42)));
+        let request = Request::new(Default::default(), None, String::from("Hello"));
+        let mut progress = Vec::new();
+
+        let (response, _) = request
+            .stream(&backend, |text, choices| {
+                progress.push((text.to_string(), choices.map(<[String]>::to_vec)))
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(response.text, "Once");
+        assert_eq!(
+            progress,
+            vec![(
+                String::from("Once"),
+                Some(vec![String::from("a"), String::from("b")])
+            )]
+        );
+    }
 }
@@ -0,0 +1,110 @@
+use super::{dice::Check, Chapter, Memory, SharedMessage, StorySetup, SummaryLevel, WorldState};
+use crate::chat::{metering::ModelUsage, Message};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+#[derive(Serialize, Deserialize)]
+pub struct SavedStory {
+    // From the root of the history, which may be a summary, to the current chapter.
+    history: Vec<SavedMessage>,
+    text: String,
+    choices: Vec<String>,
+    #[serde(default)]
+    state: WorldState,
+    // Of the current chapter, the story continues with the same rolls.
+    #[serde(default)]
+    seed: u64,
+    #[serde(default)]
+    checks: Vec<Check>,
+    // Position in the history of the message being summarized, if any.
+    summarized: Option<usize>,
+    // Position in the history of the last arc being condensed into the synopsis, if any.
+    #[serde(default)]
+    condensed: Option<usize>,
+    // Spent so far, per model.
+    #[serde(default)]
+    pub usage: BTreeMap<String, ModelUsage>,
+    // Named in the prompts of the next chapters.
+    #[serde(default = "default_player")]
+    pub player: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedMessage {
+    message: Message,
+    total_tokens: Option<u32>,
+    #[serde(default)]
+    summary: Option<SummaryLevel>,
+    #[serde(default)]
+    pinned: bool,
+}
+
+impl SavedStory {
+    pub fn new(
+        chapter: &Chapter,
+        memory: &Memory,
+        usage: BTreeMap<String, ModelUsage>,
+        player: &str,
+    ) -> Self {
+        let chain = chapter.message().chain();
+        let history = chain
+            .iter()
+            .map(|shared| {
+                let linked_message = shared.read();
+                SavedMessage {
+                    message: linked_message.message.clone(),
+                    total_tokens: linked_message.total_tokens,
+                    summary: linked_message.summary,
+                    pinned: linked_message.pinned,
+                }
+            })
+            .collect();
+        let position = |message: Option<&SharedMessage>| {
+            message.and_then(|message| chain.iter().position(|shared| shared.ptr_eq(message)))
+        };
+
+        Self {
+            history,
+            text: chapter.text().clone(),
+            choices: chapter.choices().clone(),
+            state: chapter.state().clone(),
+            seed: chapter.seed(),
+            checks: chapter.checks().clone(),
+            summarized: position(memory.arc()),
+            condensed: position(memory.synopsis()),
+            usage,
+            player: player.to_string(),
+        }
+    }
+
+    // Rebuilds the current chapter and the messages that were being summarized and condensed.
+    pub fn restore(self) -> Option<(Chapter, Option<SharedMessage>, Option<SharedMessage>)> {
+        let mut chain: Vec<SharedMessage> = Vec::new();
+
+        for saved in self.history {
+            let parent = chain.last().cloned();
+            let shared = SharedMessage::new(saved.message, parent, saved.total_tokens);
+            shared.write().summary = saved.summary;
+            shared.write().pinned = saved.pinned;
+            chain.push(shared);
+        }
+
+        let message = chain.last()?.clone();
+        let at = |position: Option<usize>| position.and_then(|index| chain.get(index).cloned());
+        let chapter = Chapter::new(
+            self.text,
+            self.choices,
+            message,
+            self.state,
+            self.seed,
+            self.checks,
+        );
+
+        Some((chapter, at(self.summarized), at(self.condensed)))
+    }
+}
+
+// Saves made before the player was kept.
+fn default_player() -> String {
+    StorySetup::default().player().to_string()
+}
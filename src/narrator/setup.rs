@@ -0,0 +1,77 @@
+use super::templates;
+use crate::chat::{Message, Role};
+use serde::Deserialize;
+
+// How the reader wants the story to be, asked before it starts or read from the configuration.
+// Anything left out is up to the model.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorySetup {
+    pub genre: Option<String>,
+    pub setting: Option<String>,
+    pub tone: Option<String>,
+    // Such as "young children" or "adult".
+    pub reading_level: Option<String>,
+    pub protagonist: Option<String>,
+    // Of the protagonist.
+    pub background: Option<String>,
+}
+
+impl StorySetup {
+    // How the templates name the protagonist.
+    pub fn player(&self) -> &str {
+        self.protagonist.as_deref().unwrap_or("the reader")
+    }
+
+    // Sent before the initial prompt, at the root of the history so that every chapter follows
+    // it.
+    pub fn message(&self) -> Option<Message> {
+        let details = [
+            ("Genre", &self.genre),
+            ("Setting", &self.setting),
+            ("Tone", &self.tone),
+            ("Reading level", &self.reading_level),
+            ("Name of the protagonist", &self.protagonist),
+            ("Background of the protagonist", &self.background),
+        ];
+        let lines: Vec<String> = details
+            .iter()
+            .filter_map(|(name, value)| {
+                let value = value.as_deref().map(str::trim).filter(|v| !v.is_empty())?;
+                Some(format!("- {}: {}", name, value))
+            })
+            .collect();
+
+        match lines.is_empty() {
+            true => None,
+            false => Some(Message::new(
+                Role::System,
+                Some(templates().render(
+                    "setup.txt",
+                    &[("settings", &lines.join("\n")), ("player", self.player())],
+                )),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_lists_the_chosen_settings() {
+        let setup = StorySetup {
+            genre: Some(String::from("fantasy")),
+            tone: Some(String::from("  ")),
+            protagonist: Some(String::from("Ayla")),
+            ..Default::default()
+        };
+
+        let message = setup.message().unwrap();
+        let content = message.content.unwrap();
+        assert_eq!(message.role, Role::System);
+        assert!(content.ends_with("- Genre: fantasy\n- Name of the protagonist: Ayla\n"));
+        assert_eq!(StorySetup::default().message(), None);
+    }
+}
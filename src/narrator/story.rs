@@ -1,85 +1,1286 @@
-use super::{message_above_threshold, Chapter, Summary};
-use crate::chat::Service;
-use tokio::task::{spawn, JoinHandle};
+use super::tree::{Branch, Lookahead, NodeId, Pending, Tree};
+use super::{
+    templates, ActionOutcome, Budget, Chapter, Memory, Preloader, Roll, SavedStory, SharedMessage,
+    StorySetup, SummaryLevel, Templates, WorldState,
+};
+use crate::chat::{metering::Ledger, request::ModelSettings, ChatBackend, Message, Role};
+use crate::config::Models;
+use crate::error::{Error, Result};
+use std::fs;
+use std::future::Future;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tokio::task::spawn;
 
-const TOKEN_THRESHOLD_FOR_REDUCE: u32 = 3500;
+// Requests the chapters following the choices of another. Preloads carry one along to preload
+// the choices of the chapter they receive.
+#[derive(Clone)]
+struct Loader<B: ChatBackend> {
+    backend: B,
+    settings: ModelSettings,
+    player: String,
+    ledger: Ledger,
+    preloader: Preloader,
+}
+
+pub struct Story<B: ChatBackend> {
+    backend: B,
+    models: Models,
+    // Shared with the backend metering the requests.
+    ledger: Ledger,
+    preloader: Preloader,
+    tree: Tree,
+    current: NodeId,
+    memory: Memory,
+    // How the templates name the protagonist.
+    player: String,
+}
 
-pub struct Story {
-    service: Service,
-    current_chapter: Chapter,
-    next_chapters: Vec<JoinHandle<Chapter>>,
-    summary: Option<JoinHandle<Summary>>,
+// A chapter of the story tree, as listed to the reader.
+pub struct TreeEntry<'a> {
+    pub id: NodeId,
+    pub depth: usize,
+    // The choice leading to this chapter, None for the first one.
+    pub choice: Option<&'a str>,
+    pub current: bool,
 }
 
-impl Story {
-    pub async fn new(service: Service) -> Self {
-        let content = include_str!("initial_prompt.txt").to_string();
-        let chapter = Chapter::load(&service, None, content).await;
+impl<B: ChatBackend> Story<B> {
+    #[cfg(test)]
+    pub async fn new(backend: B) -> Result<Self> {
+        let (models, ledger, preloader, setup) = Default::default();
+        Self::streamed(backend, models, ledger, preloader, &setup, 0, |_, _| ()).await
+    }
+
+    // Starts the story, reporting the first chapter as it is being written. The same seed rolls
+    // the same dice.
+    pub async fn streamed(
+        backend: B,
+        models: Models,
+        ledger: Ledger,
+        preloader: Preloader,
+        setup: &StorySetup,
+        seed: u64,
+        on_progress: impl FnMut(&str, Option<&[String]>) + Send,
+    ) -> Result<Self> {
+        let content = templates().render("initial_prompt.txt", &[("player", setup.player())]);
+        let settings = models.chapter.clone();
+        let root = pinned_messages(setup);
+        let state = WorldState::default();
+        let chapter =
+            Chapter::stream(&backend, settings, root, content, state, seed, on_progress).await?;
 
         let mut story = Self {
-            service,
-            current_chapter: chapter,
-            next_chapters: Vec::new(),
-            summary: None,
+            backend,
+            memory: Memory::new(&models.chapter),
+            models,
+            ledger,
+            preloader,
+            tree: Tree::new(chapter),
+            current: 0,
+            player: setup.player().to_string(),
         };
 
         story.preload_next_chapters();
-        story
+        Ok(story)
+    }
+
+    // Only the current chapter and its history are saved. Preloaded chapters are requested
+    // again once the story is loaded, other branches are lost.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let usage = self.ledger.usage();
+        let saved = SavedStory::new(self.current_chapter(), &self.memory, usage, &self.player);
+
+        fs::write(path, serde_json::to_string_pretty(&saved)?)?;
+        Ok(())
+    }
+
+    pub fn load(
+        backend: B,
+        models: Models,
+        ledger: Ledger,
+        preloader: Preloader,
+        path: impl AsRef<Path>,
+    ) -> Result<Self> {
+        let mut saved: SavedStory = serde_json::from_str(&fs::read_to_string(path)?)?;
+        ledger.restore(std::mem::take(&mut saved.usage));
+        let player = std::mem::take(&mut saved.player);
+        let (chapter, summarized, condensed) = saved
+            .restore()
+            .ok_or_else(|| Error::InvalidSave(String::from("empty history")))?;
+
+        let mut story = Self {
+            backend,
+            memory: Memory::new(&models.chapter),
+            models,
+            ledger,
+            preloader,
+            tree: Tree::new(chapter),
+            current: 0,
+            player,
+        };
+
+        let (backend, settings) = (&story.backend, &story.models.summary);
+        if let Some(message) = summarized {
+            story
+                .memory
+                .summarize(backend, settings, message, SummaryLevel::Arc);
+        }
+        if let Some(message) = condensed {
+            story
+                .memory
+                .summarize(backend, settings, message, SummaryLevel::Synopsis);
+        }
+        story.preload_next_chapters();
+        Ok(story)
+    }
+
+    pub fn state(&self) -> &WorldState {
+        self.current_chapter().state()
+    }
+
+    // The roll deciding the outcome of a choice of the current chapter, if it is a check.
+    pub fn roll(&self, index: usize) -> Option<Roll> {
+        self.current_chapter().roll(index)
     }
 
     pub fn chapter(&self) -> (&String, &Vec<String>) {
-        (self.current_chapter.text(), &self.current_chapter.choices())
+        let chapter = self.current_chapter();
+        (chapter.text(), chapter.choices())
+    }
+
+    // Whether the chapter following each choice of the current one has already been generated,
+    // preloaded or followed.
+    pub fn explored(&self) -> Vec<bool> {
+        let node = self.tree.node(self.current);
+        node.branches
+            .iter()
+            .take(node.chapter.choices().len())
+            .map(Branch::is_received)
+            .collect()
     }
 
     pub fn loaded(&self, index: usize) -> bool {
-        self.next_chapters[index].is_finished()
+        self.tree.node(self.current).branches[index].is_ready()
     }
 
-    pub async fn choose(&mut self, index: usize) {
-        let chapter = self.next_chapters.swap_remove(index).await.unwrap();
+    // On failure the chapter is requested again in the background, so choosing it once more
+    // retries.
+    pub async fn choose(&mut self, index: usize) -> Result<()> {
+        let branch =
+            std::mem::replace(self.tree.branch_mut(self.current, index), Branch::NotLoaded);
+        let mut pending = match branch {
+            Branch::Loaded(id) => {
+                self.visit(id).await;
+                return Ok(());
+            }
+            Branch::Pending(pending) => pending,
+            Branch::NotLoaded => self.load_next_chapter(index),
+        };
+
+        let chapter = match (&mut pending.join_handle).await {
+            Ok(Ok(chapter)) => chapter,
+            Ok(Err(error)) => return Err(self.reload(index, error)),
+            Err(error) => return Err(self.reload(index, error.into())),
+        };
 
+        let branches = pending.take_branches();
+        let id = self.tree.add(self.current, index, chapter, branches);
+        self.visit(id).await;
+        Ok(())
+    }
+
+    // Follows an action written by the reader instead of one of the choices. It is loaded on
+    // demand and reported as it is being written. A rejected action leaves the story on the
+    // current chapter.
+    pub async fn act(
+        &mut self,
+        action: &str,
+        on_progress: impl FnMut(&str, Option<&[String]>) + Send,
+    ) -> Result<Option<ActionOutcome>> {
+        let chapter = self.current_chapter();
+        let content = next_chapter(action, &self.player, chapter.state(), None);
+        let (parent, state) = (Some(chapter.message().clone()), chapter.state().clone());
+        let seed = chapter.next_seed(self.tree.node(self.current).branches.len());
+        let settings = self.models.chapter.clone();
+        let chapter = Chapter::stream(
+            &self.backend,
+            settings,
+            parent,
+            content,
+            state,
+            seed,
+            on_progress,
+        )
+        .await?;
+        let outcome = chapter.action().cloned();
+
+        if let Some(ActionOutcome::Rejected { .. }) = outcome {
+            return Ok(outcome);
+        }
+
+        let id = self
+            .tree
+            .add_action(self.current, action.to_string(), chapter);
+        self.visit(id).await;
+        Ok(outcome)
+    }
+
+    // Goes back up to `steps` chapters and returns how many were undone.
+    //
+    // Summaries replace the messages they cover in place, so going back before a history
+    // reduction keeps the chapters as they were displayed, but the next ones are requested with
+    // the summary instead of the chapters it covers.
+    pub fn undo(&mut self, steps: usize) -> usize {
+        let mut id = self.current;
+        let mut undone = 0;
+
+        while let (true, Some((parent, _))) = (undone < steps, self.tree.node(id).parent) {
+            id = parent;
+            undone += 1;
+        }
+
+        if undone > 0 {
+            self.jump(id);
+        }
+        undone
+    }
+
+    // Moves to any chapter of the story tree. Returns false if it does not exist.
+    pub fn goto(&mut self, id: NodeId) -> bool {
+        self.tree.promote_received();
+        if self.tree.get(id).is_none() {
+            return false;
+        }
+
+        self.jump(id);
+        true
+    }
+
+    pub fn tree(&mut self) -> Vec<TreeEntry<'_>> {
+        self.tree.promote_received();
+        self.tree
+            .walk()
+            .into_iter()
+            .map(|(id, depth)| TreeEntry {
+                id,
+                depth,
+                choice: self
+                    .tree
+                    .node(id)
+                    .parent
+                    .map(|(parent, index)| self.tree.node(parent).label(index)),
+                current: id == self.current,
+            })
+            .collect()
+    }
+
+    fn current_chapter(&self) -> &Chapter {
+        &self.tree.node(self.current).chapter
+    }
+
+    // The reader has moved on once the chapter is loaded, a history that could not be reduced
+    // is only reported in the logs.
+    async fn visit(&mut self, id: NodeId) {
+        let chapter = self.tree.node(id).chapter.clone();
         self.handle_token_thresholds(&chapter).await;
-        self.current_chapter = chapter;
+        self.tree.abort_pending(self.current);
+        self.current = id;
+        self.preload_next_chapters();
+    }
+
+    fn jump(&mut self, id: NodeId) {
+        self.tree.abort_pending(self.current);
+        self.current = id;
+        self.memory
+            .discard_unrelated(self.tree.node(id).chapter.message());
         self.preload_next_chapters();
     }
 
+    fn reload(&mut self, index: usize, error: Error) -> Error {
+        let pending = self.load_next_chapter(index);
+        *self.tree.branch_mut(self.current, index) = Branch::Pending(pending);
+        error
+    }
+
+    // Branches already loaded or being loaded are kept. Nothing is preloaded once the budget is
+    // spent, chapters are then only requested when chosen. With a lookahead, the choices of the
+    // chapters being preloaded come next, within the same request budget.
     fn preload_next_chapters(&mut self) {
-        self.next_chapters = self
-            .current_chapter
-            .choices()
-            .iter()
-            .map(|choice| {
-                let service = self.service.clone();
-                let content = format!(include_str!("next_chapter.txt"), choice.clone());
-                let parent = Some(self.current_chapter.message().clone());
+        if self.ledger.over_budget() {
+            return;
+        }
+
+        let loader = self.loader();
+        let budget = self.preloader.budget();
+        let chapter = self.current_chapter().clone();
+        let choices = self.preloader.choices(chapter.choices().len());
+
+        for index in choices.clone() {
+            let branch = self.tree.branch_mut(self.current, index);
+            if let (Branch::NotLoaded, true) = (&branch, budget.take()) {
+                *branch = Branch::Pending(loader.preload(&chapter, index));
+            }
+        }
+
+        for index in choices {
+            if let Branch::Pending(pending) = self.tree.branch_mut(self.current, index) {
+                loader.deepen(pending, &budget);
+            }
+        }
+    }
 
-                spawn(async move { Chapter::load(&service, parent, content).await })
+    // Chosen chapters do not wait for the preloads.
+    fn load_next_chapter(&self, index: usize) -> Pending {
+        let request = self.loader().request(self.current_chapter(), index);
+        Pending::new(spawn(request), Arc::default())
+    }
+
+    fn loader(&self) -> Loader<B> {
+        Loader {
+            backend: self.backend.clone(),
+            settings: self.models.chapter.clone(),
+            player: self.player.clone(),
+            ledger: self.ledger.clone(),
+            preloader: self.preloader.clone(),
+        }
+    }
+
+    async fn handle_token_thresholds(&mut self, chapter: &Chapter) {
+        let settings = &self.models.summary;
+        self.memory
+            .update(&self.backend, settings, chapter.message())
+            .await
+    }
+}
+
+impl<B: ChatBackend> Loader<B> {
+    fn request(&self, chapter: &Chapter, index: usize) -> impl Future<Output = Result<Chapter>> {
+        let backend = self.backend.clone();
+        let roll = chapter.roll(index);
+        let choice = &chapter.choices()[index];
+        let content = next_chapter(choice, &self.player, chapter.state(), roll.as_ref());
+        let (parent, state) = (Some(chapter.message().clone()), chapter.state().clone());
+        let seed = chapter.next_seed(index);
+        let settings = self.settings.clone();
+
+        async move { Chapter::load(&backend, settings, parent, content, state, seed).await }
+    }
+
+    // The choices of the chapter are only preloaded once deepened.
+    fn preload(&self, chapter: &Chapter, index: usize) -> Pending {
+        let request = self.request(chapter, index);
+        let lookahead = Arc::new(Mutex::new(Lookahead::default()));
+        let (loader, shared) = (self.clone(), lookahead.clone());
+
+        let join_handle = self.preloader.spawn(async move {
+            let chapter = request.await?;
+            let mut lookahead = shared.lock().unwrap();
+
+            lookahead.chapter = Some(chapter.clone());
+            if let Some(budget) = lookahead.budget.take() {
+                lookahead.branches = loader.preload_choices(&chapter, &budget);
+            }
+            Ok(chapter)
+        });
+
+        Pending::new(join_handle, lookahead)
+    }
+
+    // Preloads the choices of a chapter being preloaded, now if it was received or as soon as it
+    // is otherwise.
+    fn deepen(&self, pending: &Pending, budget: &Budget) {
+        if !self.preloader.lookahead() {
+            return;
+        }
+
+        let mut lookahead = pending.lookahead.lock().unwrap();
+        if lookahead.budget.is_some() || !lookahead.branches.is_empty() {
+            return;
+        }
+
+        match lookahead.chapter.clone() {
+            Some(chapter) => lookahead.branches = self.preload_choices(&chapter, budget),
+            None => lookahead.budget = Some(budget.clone()),
+        }
+    }
+
+    fn preload_choices(&self, chapter: &Chapter, budget: &Budget) -> Vec<Branch> {
+        if self.ledger.over_budget() {
+            return Vec::new();
+        }
+
+        let choices = self.preloader.choices(chapter.choices().len());
+        (0..chapter.choices().len())
+            .map(|index| match choices.contains(&index) && budget.take() {
+                true => Branch::Pending(self.preload(chapter, index)),
+                false => Branch::NotLoaded,
             })
             .collect()
     }
+}
 
-    async fn handle_token_thresholds(&mut self, chapter: &Chapter) {
-        if self.summary.is_none() {
-            if let Some(message) = message_above_threshold(chapter.message().clone()) {
-                let service = self.service.clone();
-                let join_handle = spawn(Summary::new(service, message));
-                self.summary = Some(join_handle);
+// The persona of the narrator, unless its template is empty, and the settings of the story. They
+// lead every request, summaries included.
+fn pinned_messages(setup: &StorySetup) -> Option<SharedMessage> {
+    let persona = templates().render("persona.txt", &[("player", setup.player())]);
+    let persona = Some(persona)
+        .filter(|persona| !persona.trim().is_empty())
+        .map(|persona| Message::new(Role::System, Some(persona)));
+
+    [persona, setup.message()]
+        .into_iter()
+        .flatten()
+        .fold(None, |parent, message| {
+            Some(SharedMessage::pinned(message, parent))
+        })
+}
+
+// The reader's choice or action, along with the outcome of its check and what the story
+// established so far.
+fn next_chapter(choice: &str, player: &str, state: &WorldState, roll: Option<&Roll>) -> String {
+    compose(templates(), choice, player, state, roll)
+}
+
+fn compose(
+    templates: &Templates,
+    choice: &str,
+    player: &str,
+    state: &WorldState,
+    roll: Option<&Roll>,
+) -> String {
+    let name = "next_chapter.txt";
+    let prompts = [
+        ("roll", roll.map(|roll| roll.prompt(player))),
+        ("state", state.prompt(player)),
+    ];
+    let variables: Vec<(&str, &str)> = prompts
+        .iter()
+        .map(|(variable, prompt)| (*variable, prompt.as_deref().unwrap_or_default()))
+        .chain([("choice", choice), ("player", player)])
+        .collect();
+    let content = templates.render(name, &variables);
+
+    // Prompts the template does not place follow it
+    let additions: Vec<String> = prompts
+        .into_iter()
+        .filter(|(variable, _)| !templates.uses(name, variable))
+        .filter_map(|(_, prompt)| prompt)
+        .collect();
+
+    match additions.is_empty() {
+        true => content,
+        false => format!("{}\n\n{}", content.trim_end(), additions.join("\n\n")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::mock::{chapter_response, function_response, last_content, ScriptedBackend};
+    use crate::chat::{metering::Metered, request::ModelSettings, Message};
+    use crate::narrator::{preload::PreloadStrategy, PreloadSettings};
+    use serde_json::json;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    fn contents(messages: Vec<Message>) -> Vec<String> {
+        messages
+            .into_iter()
+            .map(|message| message.content.unwrap())
+            .collect()
+    }
+
+    fn persona() -> String {
+        templates().render("persona.txt", &[("player", "the reader")])
+    }
+
+    async fn wait_for_preloads<B: ChatBackend>(story: &Story<B>) {
+        while !(0..story.chapter().1.len()).all(|index| story.loaded(index)) {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn it_starts_from_the_initial_prompt() {
+        let backend = ScriptedBackend::story(100);
+        let story = Story::new(backend.clone()).await.unwrap();
+        let prompt = templates().render("initial_prompt.txt", &[("player", "the reader")]);
+        let prompt = prompt.trim();
+
+        let (text, choices) = story.chapter();
+        assert_eq!(text, &format!("After {}", prompt));
+        assert_eq!(choices.len(), 2);
+        let messages = &backend.requests()[0].messages;
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].role, Role::System);
+        assert_eq!(messages[0].content, Some(persona()));
+    }
+
+    #[tokio::test]
+    async fn it_keeps_the_setup_at_the_root_of_the_history() {
+        let backend = ScriptedBackend::story(100);
+        let setup = StorySetup {
+            genre: Some(String::from("space opera")),
+            ..Default::default()
+        };
+        let (models, ledger, preloader) = Default::default();
+        let mut story = Story::streamed(
+            backend.clone(),
+            models,
+            ledger,
+            preloader,
+            &setup,
+            0,
+            |_, _| (),
+        )
+        .await
+        .unwrap();
+
+        story.choose(0).await.unwrap();
+
+        for request in backend.requests() {
+            assert_eq!(request.messages[0].content, Some(persona()));
+            assert_eq!(request.messages[1], setup.message().unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn it_preloads_one_chapter_per_choice() {
+        let backend = ScriptedBackend::story(100);
+        let story = Story::new(backend.clone()).await.unwrap();
+
+        wait_for_preloads(&story).await;
+
+        let requests = backend.requests();
+        assert_eq!(story.explored().len(), 2);
+        assert_eq!(requests.len(), 3);
+        for request in &requests[1..] {
+            assert_eq!(request.messages.len(), 3);
+            assert_eq!(
+                request.messages[1].content.as_ref(),
+                Some(story.chapter().0)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn it_follows_the_chosen_branch() {
+        let backend = ScriptedBackend::story(100);
+        let mut story = Story::new(backend).await.unwrap();
+        let first_text = story.chapter().0.clone();
+        let choice = story.chapter().1[1].clone();
+
+        story.choose(1).await.unwrap();
+
+        let (text, choices) = story.chapter();
+        assert_eq!(text, &format!("After {}", choice));
+        assert_eq!(choices[0], format!("{} A", choice));
+
+        // User choices are not kept in the history
+        let history = story.current_chapter().message().read().messages();
+        assert_eq!(contents(history), vec![persona(), first_text, text.clone()]);
+    }
+
+    #[tokio::test]
+    async fn it_reduces_the_history_once_above_threshold() {
+        let backend = ScriptedBackend::story(760);
+        let mut story = Story::new(backend.clone()).await.unwrap();
+
+        for _ in 0..3 {
+            story.choose(0).await.unwrap();
+        }
+
+        let history = story.current_chapter().message().read().messages();
+        // The persona is kept ahead of the summary
+        assert_eq!(history.len(), 5);
+        assert_eq!(history[0].content, Some(persona()));
+        assert_eq!(history[1].content.as_deref(), Some("SUMMARY"));
+        assert!(story.memory.arc().is_none());
+
+        let summary_requests = backend
+            .requests()
+            .into_iter()
+            .filter(|body| body.tools.is_none())
+            .count();
+        assert_eq!(summary_requests, 1);
+    }
+
+    #[tokio::test]
+    async fn it_condenses_the_arcs_of_a_long_story() {
+        let path =
+            std::env::temp_dir().join(format!("story_teller_arcs_{}.json", std::process::id()));
+        let backend = ScriptedBackend::story(1000);
+        let mut story = Story::new(backend.clone()).await.unwrap();
+        for _ in 0..20 {
+            story.choose(0).await.unwrap();
+        }
+
+        let levels = |story: &Story<ScriptedBackend>| {
+            let chain = story.current_chapter().message().chain();
+            let levels = chain.iter().map(|shared| shared.read().summary);
+            levels.collect::<Vec<_>>()
+        };
+        let history = levels(&story);
+        assert!(story.current_chapter().message().chain()[0].read().pinned);
+        assert_eq!(history[1], Some(SummaryLevel::Synopsis));
+        assert!(history[2..].contains(&Some(SummaryLevel::Arc)));
+        assert_eq!(history.last(), Some(&None));
+
+        story.save(&path).unwrap();
+        let (models, ledger, preloader) = Default::default();
+        let loaded = Story::load(backend, models, ledger, preloader, &path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(levels(&loaded), history);
+    }
+
+    #[tokio::test]
+    async fn it_uses_the_model_of_each_task() {
+        let backend = ScriptedBackend::story(1000);
+        let models = Models {
+            chapter: ModelSettings {
+                model: String::from("writer"),
+                temperature: Some(1.1),
+                ..Default::default()
+            },
+            summary: ModelSettings {
+                model: String::from("summarizer"),
+                ..Default::default()
+            },
+        };
+        let mut story = Story::streamed(
+            backend.clone(),
+            models,
+            Ledger::default(),
+            Preloader::default(),
+            &StorySetup::default(),
+            0,
+            |_, _| (),
+        )
+        .await
+        .unwrap();
+        story.choose(0).await.unwrap();
+        story.choose(0).await.unwrap();
+
+        for body in backend.requests() {
+            match body.tools {
+                Some(_) => assert_eq!(body.settings.model, "writer"),
+                None => assert_eq!(body.settings.model, "summarizer"),
             }
         }
+        assert_eq!(backend.requests()[0].settings.temperature, Some(1.1));
+    }
 
-        if let Some(value) = chapter.message().read().total_tokens {
-            if value > TOKEN_THRESHOLD_FOR_REDUCE {
-                self.reduce_history().await;
+    #[tokio::test]
+    async fn it_moves_on_when_the_history_cannot_be_reduced() {
+        let backend = ScriptedBackend::new(|body| match body.tools {
+            Some(_) => {
+                let content = last_content(body);
+                Ok(chapter_response(&content, &["a", "b"], 4000))
             }
+            None => Err(Error::NotRecorded(String::from("summary"))),
+        });
+        let mut story = Story::new(backend).await.unwrap();
+
+        for _ in 0..3 {
+            story.choose(0).await.unwrap();
         }
+
+        let current = story.tree().into_iter().find(|entry| entry.current);
+        assert_eq!(current.unwrap().depth, 3);
+    }
+
+    #[tokio::test]
+    async fn it_retries_a_chapter_that_failed_to_load() {
+        let failing = AtomicBool::new(true);
+        let backend = ScriptedBackend::new(move |body| {
+            let content = last_content(body);
+            if content.contains("broken") && failing.swap(false, Ordering::SeqCst) {
+                return Err(Error::NotRecorded(content));
+            }
+            Ok(chapter_response(&content, &["broken", "fine"], 10))
+        });
+        let mut story = Story::new(backend).await.unwrap();
+
+        assert!(matches!(story.choose(0).await, Err(Error::NotRecorded(_))));
+        story.choose(0).await.unwrap();
+
+        assert_eq!(story.chapter().0.trim(), "broken");
+    }
+
+    #[tokio::test]
+    async fn it_resumes_a_saved_story() {
+        let path =
+            std::env::temp_dir().join(format!("story_teller_save_{}.json", std::process::id()));
+        let backend = ScriptedBackend::story(760);
+        let mut story = Story::new(backend.clone()).await.unwrap();
+        story.choose(0).await.unwrap();
+        story.choose(1).await.unwrap();
+        assert!(story.memory.arc().is_some());
+
+        story.save(&path).unwrap();
+        let loaded = Story::load(
+            backend,
+            Models::default(),
+            Ledger::default(),
+            Preloader::default(),
+            &path,
+        )
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.chapter(), story.chapter());
+        assert_eq!(loaded.explored().len(), 2);
+        assert_eq!(loaded.player, story.player);
+
+        let history = |story: &Story<ScriptedBackend>| {
+            let linked_message = story.current_chapter().message().read().clone();
+            (linked_message.messages(), linked_message.total_tokens)
+        };
+        assert_eq!(history(&loaded), history(&story));
+
+        let summarized = loaded.memory.arc().unwrap();
+        let chain = loaded.current_chapter().message().chain();
+        assert!(summarized.ptr_eq(&chain[1]));
+        assert!(chain[0].read().pinned);
     }
 
-    async fn reduce_history(&mut self) {
-        let result = self.summary.take().unwrap().await;
-        let summary = result.unwrap();
-        let mut message = summary.message.write();
+    #[tokio::test]
+    async fn it_resumes_the_usage_of_a_saved_story() {
+        let path =
+            std::env::temp_dir().join(format!("story_teller_usage_{}.json", std::process::id()));
+        let ledger = Ledger::default();
+        let backend = Metered::new(ScriptedBackend::story(100), ledger.clone());
+        let story = Story::streamed(
+            backend.clone(),
+            Models::default(),
+            ledger.clone(),
+            Preloader::default(),
+            &StorySetup::default(),
+            0,
+            |_, _| (),
+        )
+        .await
+        .unwrap();
+        wait_for_preloads(&story).await;
+
+        story.save(&path).unwrap();
+        let restored = Ledger::default();
+        Story::load(
+            backend,
+            Models::default(),
+            restored.clone(),
+            Preloader::default(),
+            &path,
+        )
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(restored.usage(), ledger.usage());
+        assert_eq!(
+            restored.usage()[&ModelSettings::default().model].requests,
+            3
+        );
+    }
+
+    #[tokio::test]
+    async fn it_stops_preloading_once_over_budget() {
+        let ledger = Ledger::new(Default::default(), Some(0.0001));
+        let backend = ScriptedBackend::story(100);
+        let metered = Metered::new(backend.clone(), ledger.clone());
+        let mut story = Story::streamed(
+            metered,
+            Models::default(),
+            ledger,
+            Preloader::default(),
+            &StorySetup::default(),
+            0,
+            |_, _| (),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(story.explored(), vec![false, false]);
+        assert!(!story.loaded(0));
+        story.choose(0).await.unwrap();
+
+        assert_eq!(backend.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn it_fails_to_load_a_missing_save() {
+        let result = Story::load(
+            ScriptedBackend::story(100),
+            Models::default(),
+            Ledger::default(),
+            Preloader::default(),
+            "/nonexistent/story.json",
+        );
+
+        assert!(matches!(result, Err(Error::Io(_))));
+    }
+
+    #[tokio::test]
+    async fn it_goes_back_to_previous_chapters() {
+        let backend = ScriptedBackend::story(100);
+        let mut story = Story::new(backend).await.unwrap();
+        let first = story.chapter().0.clone();
+        story.choose(0).await.unwrap();
+        let second = story.chapter().0.clone();
+        story.choose(1).await.unwrap();
+        story.choose(0).await.unwrap();
+
+        assert_eq!(story.undo(2), 2);
+        assert_eq!(story.chapter().0, &second);
+        assert_eq!(story.undo(5), 1);
+        assert_eq!(story.chapter().0, &first);
+        assert_eq!(story.undo(1), 0);
+
+        story.choose(1).await.unwrap();
+        let history = story.current_chapter().message().read().messages();
+        assert_eq!(contents(history).len(), 3);
+    }
+
+    #[tokio::test]
+    async fn it_goes_back_before_the_history_reduction() {
+        let backend = ScriptedBackend::story(520);
+        let mut story = Story::new(backend).await.unwrap();
+        for _ in 0..5 {
+            story.choose(0).await.unwrap();
+        }
+
+        // The first chapter has been replaced by the summary of the first arc, after the persona
+        story.undo(3);
+        let history = story.current_chapter().message().read().messages();
+        assert_eq!(history.len(), 4);
+        assert_eq!(history[1].content.as_deref(), Some("SUMMARY"));
+
+        // Summarizing the second chapter as the next arc is only relevant while it is part of the
+        // history
+        story.choose(0).await.unwrap();
+        assert!(story.memory.arc().is_some());
+        story.undo(2);
+        assert!(story.memory.arc().is_some());
+        story.undo(1);
+        assert!(story.memory.arc().is_none());
+        let history = story.current_chapter().message().read().messages();
+        assert_eq!(history.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn it_requests_with_the_summary_after_going_back() {
+        let mut story = Story::new(ScriptedBackend::story(800)).await.unwrap();
+        let first = story.chapter().0.clone();
+        for _ in 0..3 {
+            story.choose(0).await.unwrap();
+        }
+
+        story.undo(3);
+        assert_eq!(story.chapter().0, &first);
+        let history = story.current_chapter().message().read().messages();
+        assert_eq!(contents(history), vec![persona(), String::from("SUMMARY")]);
+
+        // Even for the branch preloaded before the reduction
+        story.choose(1).await.unwrap();
+        let history = story.current_chapter().message().read().messages();
+        assert_eq!(contents(history)[..2], [persona(), String::from("SUMMARY")]);
+    }
+
+    #[tokio::test]
+    async fn it_keeps_the_branches_not_taken() {
+        let backend = ScriptedBackend::story(100);
+        let mut story = Story::new(backend.clone()).await.unwrap();
+        wait_for_preloads(&story).await;
+        story.choose(0).await.unwrap();
+        let first_branch = story.chapter().0.clone();
+        wait_for_preloads(&story).await;
+        let requests = backend.requests().len();
+
+        story.undo(1);
+        assert_eq!(story.explored(), vec![true, true]);
+        story.choose(1).await.unwrap();
+        story.undo(1);
+        story.choose(0).await.unwrap();
+
+        // Both branches of the first chapter were loaded when it was first displayed, and so
+        // were the ones of the first branch
+        assert_eq!(story.chapter().0, &first_branch);
+        assert_eq!(backend.requests().len(), requests);
+        assert_eq!(story.explored(), vec![true, true]);
+    }
+
+    #[tokio::test]
+    async fn it_aborts_the_preloads_not_taken() {
+        let mut story = Story::new(ScriptedBackend::story(100)).await.unwrap();
+        let never = spawn(std::future::pending());
+        let abort_handle = never.abort_handle();
+        *story.tree.branch_mut(0, 1) = Branch::Pending(Pending::new(never, Arc::default()));
+
+        story.choose(0).await.unwrap();
+        tokio::task::yield_now().await;
+
+        assert!(abort_handle.is_finished());
+        assert!(matches!(story.tree.node(0).branches[1], Branch::NotLoaded));
+    }
+
+    async fn lookahead(settings: PreloadSettings) -> (Story<ScriptedBackend>, ScriptedBackend) {
+        let backend = ScriptedBackend::story(100);
+        let (models, ledger, preloader) = (
+            Models::default(),
+            Ledger::default(),
+            Preloader::new(&settings),
+        );
+        let story = Story::streamed(
+            backend.clone(),
+            models,
+            ledger,
+            preloader,
+            &StorySetup::default(),
+            0,
+            |_, _| (),
+        )
+        .await
+        .unwrap();
+        // Every preload is answered as soon as it runs
+        for _ in 0..100 {
+            tokio::task::yield_now().await;
+        }
+
+        (story, backend)
+    }
+
+    #[tokio::test]
+    async fn it_preloads_two_chapters_ahead() {
+        let (mut story, backend) = lookahead(PreloadSettings {
+            depth: 2,
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(backend.requests().len(), 7);
+
+        story.choose(1).await.unwrap();
+
+        assert_eq!(backend.requests().len(), 7);
+        assert!(story.loaded(0) && story.loaded(1));
+        let content = last_content(&backend.requests()[6]);
+        assert_eq!(
+            content,
+            templates().render("next_chapter.txt", &[("choice", &story.chapter().1[1])])
+        );
+    }
+
+    #[tokio::test]
+    async fn it_stops_looking_ahead_once_the_requests_are_spent() {
+        let (_, backend) = lookahead(PreloadSettings {
+            depth: 2,
+            max_requests: Some(3),
+            ..Default::default()
+        })
+        .await;
+
+        assert_eq!(backend.requests().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn it_aborts_the_lookahead_of_the_branches_not_taken() {
+        let mut story = Story::new(ScriptedBackend::story(100)).await.unwrap();
+        wait_for_preloads(&story).await;
+        let never = spawn(std::future::pending());
+        let abort_handle = never.abort_handle();
+        if let Branch::Pending(pending) = story.tree.branch_mut(0, 1) {
+            let mut lookahead = pending.lookahead.lock().unwrap();
+            lookahead.branches = vec![Branch::Pending(Pending::new(never, Arc::default()))];
+        }
+
+        story.choose(0).await.unwrap();
+        tokio::task::yield_now().await;
+
+        assert!(abort_handle.is_finished());
+        assert!(story.tree.node(0).branches[1].is_ready());
+    }
+
+    #[tokio::test]
+    async fn it_keeps_the_lookahead_received_for_the_branches_not_taken() {
+        let (mut story, _) = lookahead(PreloadSettings {
+            depth: 2,
+            ..Default::default()
+        })
+        .await;
+
+        story.choose(1).await.unwrap();
+
+        let not_taken = match story.tree.node(0).branches[0] {
+            Branch::Loaded(id) => id,
+            _ => panic!("Expected the preloaded chapter in the tree"),
+        };
+        // Listed, and so reachable with goto
+        let ids: Vec<NodeId> = story.tree().into_iter().map(|entry| entry.id).collect();
+        let grandchildren = ids.into_iter().filter(|id| {
+            let parent = story.tree.node(*id).parent;
+            parent.is_some_and(|(parent, _)| parent == not_taken)
+        });
+        assert_eq!(grandchildren.count(), 2);
+    }
+
+    #[tokio::test]
+    async fn it_preloads_the_choices_of_the_strategy() {
+        let preloader = |strategy| {
+            Preloader::new(&PreloadSettings {
+                strategy,
+                ..Default::default()
+            })
+        };
+        let pending = |story: &Story<ScriptedBackend>| {
+            let branches = &story.tree.node(story.current).branches;
+            let pending = branches
+                .iter()
+                .map(|branch| matches!(branch, Branch::Pending(_)));
+            pending.collect::<Vec<_>>()
+        };
+
+        for (strategy, expected) in [
+            (PreloadStrategy::All, vec![true, true]),
+            (PreloadStrategy::None, vec![false, false]),
+            (PreloadStrategy::First(1), vec![true, false]),
+        ] {
+            let (models, ledger) = (Models::default(), Ledger::default());
+            let backend = ScriptedBackend::story(100);
+            let story = Story::streamed(
+                backend,
+                models,
+                ledger,
+                preloader(strategy),
+                &StorySetup::default(),
+                0,
+                |_, _| (),
+            )
+            .await
+            .unwrap();
+
+            assert_eq!(pending(&story), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn it_jumps_to_any_chapter_of_the_tree() {
+        let preloader = Preloader::new(&PreloadSettings {
+            strategy: PreloadStrategy::None,
+            ..Default::default()
+        });
+        let mut story = Story::streamed(
+            ScriptedBackend::story(100),
+            Models::default(),
+            Ledger::default(),
+            preloader,
+            &StorySetup::default(),
+            0,
+            |_, _| (),
+        )
+        .await
+        .unwrap();
+        story.choose(0).await.unwrap();
+        story.choose(0).await.unwrap();
+        story.undo(2);
+        story.choose(1).await.unwrap();
+
+        let entries: Vec<(NodeId, usize, bool)> = story
+            .tree()
+            .iter()
+            .map(|entry| (entry.id, entry.depth, entry.current))
+            .collect();
+        assert_eq!(
+            entries,
+            vec![(0, 0, false), (1, 1, false), (2, 2, false), (3, 1, true)]
+        );
+
+        let text = story.tree.node(2).chapter.text().clone();
+        assert!(story.goto(2));
+        assert_eq!(story.chapter().0, &text);
+        assert!(!story.goto(4));
+    }
+
+    #[tokio::test]
+    async fn it_jumps_to_a_preloaded_chapter() {
+        let mut story = Story::new(ScriptedBackend::story(100)).await.unwrap();
+        let sibling = story.chapter().1[1].clone();
+        wait_for_preloads(&story).await;
+        story.choose(0).await.unwrap();
+
+        let entry = story
+            .tree()
+            .into_iter()
+            .find(|entry| entry.depth == 1 && !entry.current);
+        let id = entry.unwrap().id;
+        assert!(story.goto(id));
+        assert_eq!(story.chapter().0, &format!("After {}", sibling));
+        assert_eq!(story.tree.node(id).parent, Some((0, 1)));
+    }
+
+    #[tokio::test]
+    async fn it_follows_an_action_written_by_the_reader() {
+        let backend = ScriptedBackend::story(100);
+        let mut story = Story::new(backend.clone()).await.unwrap();
+        wait_for_preloads(&story).await;
+        let requests = backend.requests().len();
+
+        let outcome = story.act("I search the drawer", |_, _| ()).await.unwrap();
+
+        assert_eq!(outcome, None);
+        assert_eq!(story.chapter().0, "After I search the drawer");
+        wait_for_preloads(&story).await;
+        // Only the action and the preloads of its chapter were requested
+        assert_eq!(backend.requests().len(), requests + 3);
+
+        story.undo(1);
+        assert_eq!(story.explored(), vec![true, true]);
+        let choices = story.chapter().1.clone();
+        let labels: Vec<Option<&str>> = story
+            .tree()
+            .iter()
+            .filter(|entry| entry.depth < 2)
+            .map(|entry| entry.choice)
+            .collect();
+        assert_eq!(
+            labels,
+            vec![
+                None,
+                Some(choices[0].as_str()),
+                Some(choices[1].as_str()),
+                Some("I search the drawer")
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn it_tracks_the_state_of_the_world() {
+        let backend = ScriptedBackend::new(|body| {
+            let arguments = json!({
+                "text": last_content(body),
+                "choices": ["a", "b"],
+                "state": { "items_gained": ["coin"], "stats": { "gold": 1 } },
+            });
+            Ok(function_response(arguments, 10))
+        });
+        let mut story = Story::new(backend.clone()).await.unwrap();
+        assert_eq!(story.state().inventory, vec!["coin"]);
+
+        story.choose(0).await.unwrap();
+        story.choose(1).await.unwrap();
+
+        assert_eq!(story.state().inventory, vec!["coin"; 3]);
+        assert_eq!(story.state().stats["gold"], 3);
+        let (text, _) = story.chapter();
+        assert!(text.starts_with("b\n\nState of the world"));
+        assert!(text.contains("\"gold\":2"));
+
+        story.undo(1);
+        assert_eq!(story.state().stats["gold"], 2);
+
+        let path =
+            std::env::temp_dir().join(format!("story_teller_state_{}.json", std::process::id()));
+        story.save(&path).unwrap();
+        let (models, ledger, preloader) = Default::default();
+        let loaded = Story::load(backend, models, ledger, preloader, &path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.state(), story.state());
+    }
+
+    #[tokio::test]
+    async fn it_rolls_the_dice_of_checks() {
+        let backend = ScriptedBackend::new(|body| {
+            let arguments = json!({
+                "text": last_content(body),
+                "choices": ["climb", "walk"],
+                "checks": [{ "choice": 0, "stat": "strength", "difficulty": 11 }],
+            });
+            Ok(function_response(arguments, 10))
+        });
+        let setup = StorySetup::default();
+        let start = |seed| {
+            let (models, ledger, preloader) = Default::default();
+            Story::streamed(
+                backend.clone(),
+                models,
+                ledger,
+                preloader,
+                &setup,
+                seed,
+                |_, _| (),
+            )
+        };
+        let mut story = start(42).await.unwrap();
+        let roll = story.roll(0).unwrap();
+        assert_eq!(story.roll(1), None);
+
+        story.choose(0).await.unwrap();
+        let (text, _) = story.chapter();
+        assert!(text.starts_with("climb\n\nThe reader attempted a strength check"));
+        assert!(text.contains(&format!("rolled {} + 0", roll.die)));
+        assert!(text.contains(roll.outcome()));
+
+        // The same seed rolls the same dice, at every depth
+        let mut again = start(42).await.unwrap();
+        again.choose(0).await.unwrap();
+        assert_eq!(again.roll(0), story.roll(0));
+        let rolls = |seed| async move {
+            let story = start(seed).await.unwrap();
+            story.roll(0).unwrap().die
+        };
+        let mut dice = Vec::new();
+        for seed in 0..20 {
+            dice.push(rolls(seed).await);
+        }
+        assert!(dice.iter().any(|die| *die != dice[0]));
+    }
+
+    #[tokio::test]
+    async fn it_stays_on_the_chapter_when_an_action_is_rejected() {
+        let backend = ScriptedBackend::new(|body| {
+            let content = last_content(body);
+            let arguments = match content.contains("fly") {
+                true => json!({
+                    "action": { "status": "rejected", "explanation": "You cannot fly" },
+                    "text": "You flap your arms",
+                    "choices": ["a", "b"],
+                }),
+                false => json!({ "text": content, "choices": ["a", "b"] }),
+            };
+            Ok(function_response(arguments, 10))
+        });
+        let mut story = Story::new(backend).await.unwrap();
+        let text = story.chapter().0.clone();
+
+        let outcome = story.act("I fly away", |_, _| ()).await.unwrap();
+
+        assert_eq!(
+            outcome,
+            Some(ActionOutcome::Rejected {
+                explanation: String::from("You cannot fly")
+            })
+        );
+        assert_eq!(story.chapter().0, &text);
+        assert_eq!(story.tree().len(), 1);
+    }
+
+    #[test]
+    fn it_lets_the_template_place_the_state_and_the_player() {
+        let directory =
+            std::env::temp_dir().join(format!("story_teller_placed_{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(
+            directory.join("next_chapter.txt"),
+            "{state}\n{player} chooses to {choice}",
+        )
+        .unwrap();
+        let templates = Templates::load(Some(&directory), "English").unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+        let state = WorldState {
+            location: Some(String::from("the cave")),
+            ..Default::default()
+        };
+
+        let content = compose(&templates, "go on", "Sam", &state, None);
 
-        message.parent = None;
-        message.message.content = Some(summary.content);
+        assert!(content.starts_with(&state.prompt("Sam").unwrap()));
+        assert!(content.ends_with("\nSam chooses to go on"));
     }
 }
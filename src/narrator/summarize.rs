@@ -1,11 +1,12 @@
-use super::{LinkedMessage, SharedMessage};
-use crate::chat::{request, Message, Role, Service};
+use super::{templates, LinkedMessage, SharedMessage, SummaryLevel};
+use crate::chat::{json, request, ChatBackend, Message, Role};
+use crate::error::{Error, Result};
+use reqwest::StatusCode;
 use serde::{self, Deserialize};
 
-const TOKEN_THRESHOLD_FOR_SUMMARY: u32 = 1000;
-
 pub struct Summary {
     pub message: SharedMessage,
+    pub level: SummaryLevel,
     pub content: String,
 }
 
@@ -15,75 +16,107 @@ struct SummaryResponse {
 }
 
 impl Summary {
-    pub async fn new(service: Service, message: SharedMessage) -> Self {
-        let content = summarize(&service, message.clone()).await;
-        Self { message, content }
+    pub async fn new<B: ChatBackend>(
+        backend: B,
+        settings: request::ModelSettings,
+        message: SharedMessage,
+        level: SummaryLevel,
+    ) -> Result<Self> {
+        let content = summarize(&backend, settings, message.clone(), level).await?;
+        Ok(Self {
+            message,
+            level,
+            content,
+        })
     }
 }
 
-pub fn message_above_threshold(mut message: SharedMessage) -> Option<SharedMessage> {
-    let mut selected: Option<SharedMessage> = None;
-
-    loop {
-        let (total_tokens, parent) = {
-            let read_lock = message.read();
-            (read_lock.total_tokens, read_lock.parent.clone())
-        };
-
-        match total_tokens {
-            Some(value) if value < TOKEN_THRESHOLD_FOR_SUMMARY => break,
-            Some(_) => selected = Some(message),
-            None => (),
-        }
-
-        match parent {
-            Some(value) => message = value,
-            None => break,
+// The model is told which messages are already summaries, they only lead the history.
+fn instruction(parent: &SharedMessage, level: SummaryLevel) -> String {
+    let summaries = parent
+        .chain()
+        .iter()
+        .filter(|shared| shared.read().summary.is_some())
+        .count();
+
+    match (level, summaries) {
+        (SummaryLevel::Synopsis, _) => templates().render("condense.txt", &[]),
+        (SummaryLevel::Arc, 0) => templates().render("summarize.txt", &[]),
+        (SummaryLevel::Arc, _) => {
+            let summaries = summaries.to_string();
+            templates().render("summarize_arc.txt", &[("summaries", &summaries)])
         }
     }
-
-    selected
 }
 
-async fn summarize(service: &Service, parent: SharedMessage) -> String {
-    let query = Message {
-        role: Role::User,
-        content: Some(include_str!("summarize.txt").to_string()),
-        name: None,
-        function_call: None,
-    };
+async fn summarize<B: ChatBackend>(
+    backend: &B,
+    settings: request::ModelSettings,
+    parent: SharedMessage,
+    level: SummaryLevel,
+) -> Result<String> {
+    let query = Message::new(Role::User, Some(instruction(&parent, level)));
 
     let linked_message = LinkedMessage {
         message: query,
         parent: Some(parent),
         total_tokens: None,
+        summary: None,
+        pinned: false,
     };
 
-    let body = request::Body {
+    let mut body = request::Body {
+        response_format: response_format(settings.json_format),
+        settings,
         messages: linked_message.messages(),
         ..Default::default()
     };
 
-    let api_response = service.submit(body).await;
-    let response_message = api_response.message();
-    let json_response: SummaryResponse =
-        serde_json::from_str(&response_message.content.unwrap()).unwrap();
+    // The JSON is extracted from the content anyway
+    let api_response = match backend.submit(body.clone()).await {
+        Err(Error::Http {
+            status,
+            body: reason,
+        }) if status == StatusCode::BAD_REQUEST && body.response_format.is_some() => {
+            eprintln!("Response format rejected, asking without it: {}", reason);
+            body.response_format = None;
+            backend.submit(body).await?
+        }
+        result => result?,
+    };
+    let content = api_response
+        .message()?
+        .content
+        .ok_or(Error::EmptyResponse)?;
+    let json = json::extract_object(&content).ok_or(Error::MissingJson(content))?;
+    let json_response: SummaryResponse = serde_json::from_value(json)?;
 
     eprintln!("SUMMARY: {}", json_response.summary);
-    json_response.summary
+    Ok(json_response.summary)
+}
+
+fn response_format(json_format: request::JsonFormat) -> Option<request::ResponseFormat> {
+    match json_format {
+        request::JsonFormat::None => None,
+        request::JsonFormat::JsonObject => Some(request::ResponseFormat::JsonObject),
+        request::JsonFormat::JsonSchema => Some(request::ResponseFormat::JsonSchema {
+            json_schema: request::JsonSchema {
+                name: "summary",
+                schema: templates().summary_schema().clone(),
+                strict: true,
+            },
+        }),
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::chat::mock::{summary_response, ScriptedBackend};
+    use crate::chat::{ApiResponse, Choice, Usage};
 
     fn dummy_message() -> Message {
-        Message {
-            role: Role::User,
-            content: Some("Hello, world!".to_string()),
-            name: None,
-            function_call: None,
-        }
+        Message::new(Role::User, Some("Hello, world!".to_string()))
     }
 
     fn linked_messages(total_tokens_collection: Vec<Option<u32>>) -> SharedMessage {
@@ -97,24 +130,80 @@ mod tests {
     }
 
     #[test]
-    fn it_finds_the_message_above_threshold() {
-        let msg = linked_messages(vec![Some(100), Some(1200), None, Some(2000)]);
-        let selected = message_above_threshold(msg);
-
-        let total_tokens = selected
-            .expect("selected message")
-            .read()
-            .total_tokens
-            .expect("total_tokens");
+    fn it_tells_which_messages_are_summaries() {
+        let root = linked_messages(vec![None, None]);
+        assert!(instruction(&root, SummaryLevel::Arc).starts_with("Can you summarize"));
+
+        root.write().summary = Some(SummaryLevel::Arc);
+        let message = SharedMessage::new(dummy_message(), Some(root.clone()), None);
+        assert!(instruction(&message, SummaryLevel::Arc).contains("the first 1 message(s)"));
+        assert_eq!(
+            instruction(&root, SummaryLevel::Synopsis),
+            include_str!("condense.txt")
+        );
+    }
 
-        assert_eq!(total_tokens, 1200);
+    #[tokio::test]
+    async fn it_requests_and_extracts_the_summary_json() {
+        let backend = ScriptedBackend::new(|_| {
+            let content = "Here it is:\n```json\n{\"summary\": \"Once upon a time\"}\n```";
+            Ok(ApiResponse {
+                choices: vec![Choice {
+                    message: Message::new(Role::Assistant, Some(content.to_string())),
+                }],
+                usage: Usage {
+                    total_tokens: 10,
+                    ..Default::default()
+                },
+            })
+        });
+
+        let summary = Summary::new(
+            backend.clone(),
+            Default::default(),
+            linked_messages(vec![None]),
+            SummaryLevel::Arc,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(summary.content, "Once upon a time");
+        let body = serde_json::to_value(&backend.requests()[0]).unwrap();
+        assert_eq!(body["response_format"]["type"], "json_schema");
+        assert_eq!(
+            body["response_format"]["json_schema"]["schema"]["required"],
+            serde_json::json!(["summary"])
+        );
     }
 
-    #[test]
-    fn it_returns_none_witout_message_above_threshold() {
-        let msg = linked_messages(vec![Some(100), Some(200)]);
-        let selected = message_above_threshold(msg);
+    #[tokio::test]
+    async fn it_asks_again_without_a_rejected_response_format() {
+        let backend = ScriptedBackend::new(|body| match body.response_format {
+            Some(_) => Err(Error::Http {
+                status: StatusCode::BAD_REQUEST,
+                body: String::from("response_format is not supported"),
+            }),
+            None => Ok(summary_response("Once upon a time", 10)),
+        });
+        let settings = request::ModelSettings {
+            json_format: request::JsonFormat::JsonObject,
+            ..Default::default()
+        };
 
-        assert!(selected.is_none());
+        let summary = Summary::new(
+            backend.clone(),
+            settings,
+            linked_messages(vec![None]),
+            SummaryLevel::Arc,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(summary.content, "Once upon a time");
+        let requests = backend.requests();
+        assert_eq!(requests.len(), 2);
+        let body = serde_json::to_value(&requests[0]).unwrap();
+        assert_eq!(body["response_format"]["type"], "json_object");
+        assert!(requests[1].response_format.is_none());
     }
 }
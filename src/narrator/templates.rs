@@ -0,0 +1,343 @@
+use crate::chat::request::Function;
+use crate::error::{Error, Result};
+use jsonschema::JSONSchema;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::sync::OnceLock;
+
+// Available in every template.
+const GLOBAL_VARIABLES: &[&str] = &["language"];
+
+static TEMPLATES: OnceLock<Templates> = OnceLock::new();
+
+struct Spec {
+    name: &'static str,
+    default: &'static str,
+    variables: &'static [&'static str],
+    // Without them the template would lose what the request is about.
+    required: &'static [&'static str],
+}
+
+const SPECS: &[Spec] = &[
+    Spec {
+        name: "persona.txt",
+        default: include_str!("persona.txt"),
+        variables: &["player"],
+        required: &[],
+    },
+    Spec {
+        name: "initial_prompt.txt",
+        default: include_str!("initial_prompt.txt"),
+        variables: &["player"],
+        required: &[],
+    },
+    Spec {
+        name: "setup.txt",
+        default: include_str!("setup.txt"),
+        variables: &["settings", "player"],
+        required: &["settings"],
+    },
+    Spec {
+        name: "next_chapter.txt",
+        default: include_str!("next_chapter.txt"),
+        variables: &["choice", "state", "roll", "player"],
+        required: &["choice"],
+    },
+    Spec {
+        name: "world_state.txt",
+        default: include_str!("world_state.txt"),
+        variables: &["state", "player"],
+        required: &["state"],
+    },
+    Spec {
+        name: "roll.txt",
+        default: include_str!("roll.txt"),
+        variables: &[
+            "stat",
+            "difficulty",
+            "die",
+            "modifier",
+            "total",
+            "outcome",
+            "player",
+        ],
+        required: &["outcome"],
+    },
+    Spec {
+        name: "correction.txt",
+        default: include_str!("correction.txt"),
+        variables: &["error"],
+        required: &["error"],
+    },
+    Spec {
+        name: "summarize.txt",
+        default: include_str!("summarize.txt"),
+        variables: &[],
+        required: &[],
+    },
+    // The count of summaries only, their text already leads the history sent along with it.
+    Spec {
+        name: "summarize_arc.txt",
+        default: include_str!("summarize_arc.txt"),
+        variables: &["summaries"],
+        required: &[],
+    },
+    Spec {
+        name: "condense.txt",
+        default: include_str!("condense.txt"),
+        variables: &[],
+        required: &[],
+    },
+];
+
+// The prompts sent to the model, with named variables such as `{choice}` and doubled braces for
+// literal ones. Any of them can be overridden by a file of the same name in a directory given in
+// the configuration, and they are all checked once at startup so that a broken template is
+// reported before the story starts.
+pub struct Templates {
+    texts: BTreeMap<&'static str, String>,
+    language: String,
+    chapter_function: Function,
+    summary_schema: Value,
+}
+
+impl Templates {
+    pub fn load(directory: Option<&Path>, language: &str) -> Result<Self> {
+        let read = |name: &str, default: &str| -> Result<String> {
+            match directory.map(|directory| directory.join(name)) {
+                Some(path) if path.exists() => Ok(fs::read_to_string(path)?),
+                _ => Ok(default.to_string()),
+            }
+        };
+
+        let mut texts = BTreeMap::new();
+        for spec in SPECS {
+            let text = read(spec.name, spec.default)?;
+            check(spec, &text).map_err(|reason| invalid(spec.name, reason))?;
+            texts.insert(spec.name, text);
+        }
+
+        let name = "parameters_schema.json";
+        let parameters = read(name, include_str!("parameters_schema.json"))?;
+        let parameters = serde_json::from_str(&parameters).map_err(|e| invalid(name, e))?;
+        check_required(&parameters, &["text", "choices"]).map_err(|e| invalid(name, e))?;
+        let chapter_function =
+            Function::new("chapter", None, Some(parameters)).map_err(|e| invalid(name, e))?;
+
+        let name = "summary_schema.json";
+        let summary_schema = read(name, include_str!("summary_schema.json"))?;
+        let summary_schema: Value =
+            serde_json::from_str(&summary_schema).map_err(|e| invalid(name, e))?;
+        JSONSchema::compile(&summary_schema).map_err(|e| invalid(name, e))?;
+
+        Ok(Self {
+            texts,
+            language: language.to_string(),
+            chapter_function,
+            summary_schema,
+        })
+    }
+
+    // Used by every request from then on, before any of them is made.
+    pub fn install(self) {
+        let installed = TEMPLATES.set(self).is_ok();
+        assert!(installed, "Templates installed after being used");
+    }
+
+    // Variables not given are left empty, templates have been checked to only use known ones.
+    pub fn render(&self, name: &str, variables: &[(&str, &str)]) -> String {
+        let text = self
+            .texts
+            .get(name)
+            .unwrap_or_else(|| panic!("Unknown template {}", name));
+        let value = |variable: &str| match variable {
+            "language" => Some(self.language.clone()),
+            _ => Some(
+                variables
+                    .iter()
+                    .find(|(name, _)| *name == variable)
+                    .map_or(String::new(), |(_, value)| value.to_string()),
+            ),
+        };
+
+        substitute(text, value).unwrap()
+    }
+
+    // Whether the template places the variable itself.
+    pub fn uses(&self, name: &str, variable: &str) -> bool {
+        let mut used = false;
+        substitute(&self.texts[name], |name| {
+            used |= name == variable;
+            Some(String::new())
+        })
+        .unwrap();
+        used
+    }
+
+    pub fn chapter_function(&self) -> &Function {
+        &self.chapter_function
+    }
+
+    pub fn summary_schema(&self) -> &Value {
+        &self.summary_schema
+    }
+}
+
+impl Default for Templates {
+    fn default() -> Self {
+        Self::load(None, "English").expect("Invalid embedded template")
+    }
+}
+
+// The installed templates, or the embedded ones.
+pub fn templates() -> &'static Templates {
+    TEMPLATES.get_or_init(Templates::default)
+}
+
+fn invalid(name: &str, reason: impl ToString) -> Error {
+    Error::InvalidTemplate(format!("{}: {}", name, reason.to_string()))
+}
+
+fn check(spec: &Spec, text: &str) -> std::result::Result<(), String> {
+    let mut used = Vec::new();
+    substitute(text, |variable| {
+        let known = spec.variables.contains(&variable) || GLOBAL_VARIABLES.contains(&variable);
+        used.push(variable.to_string());
+        known.then(String::new)
+    })?;
+
+    match spec
+        .required
+        .iter()
+        .find(|name| !used.iter().any(|u| u == *name))
+    {
+        Some(name) => Err(format!("missing variable {{{}}}", name)),
+        None => Ok(()),
+    }
+}
+
+// The chapters are read from these properties, a schema without them is of no use.
+fn check_required(schema: &Value, properties: &[&str]) -> std::result::Result<(), String> {
+    let required = schema["required"].as_array();
+    let missing = properties.iter().find(|property| {
+        !required.is_some_and(|required| required.iter().any(|name| name == *property))
+    });
+
+    match missing {
+        Some(property) => Err(format!("\"{}\" must be required", property)),
+        None => Ok(()),
+    }
+}
+
+// Replaces `{name}` with its value, unknown names are reported.
+fn substitute(
+    text: &str,
+    mut value: impl FnMut(&str) -> Option<String>,
+) -> std::result::Result<String, String> {
+    let mut result = String::new();
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.as_str().starts_with('{') => {
+                chars.next();
+                result.push('{');
+            }
+            '}' if chars.as_str().starts_with('}') => {
+                chars.next();
+                result.push('}');
+            }
+            '{' => {
+                let rest = chars.as_str();
+                let end = rest.find('}').ok_or("unclosed {")?;
+                let name = &rest[..end];
+                let replacement = value(name).ok_or_else(|| {
+                    format!(
+                        "unknown variable {{{}}}, braces can be escaped as {{{{",
+                        name
+                    )
+                })?;
+                result.push_str(&replacement);
+                chars = rest[end + 1..].chars();
+            }
+            '}' => return Err(String::from("unmatched }, it can be escaped as }}")),
+            c => result.push(c),
+        }
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn directory(test: &str, files: &[(&str, &str)]) -> std::path::PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("story_teller_{}_{}", test, std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        for (name, content) in files {
+            fs::write(directory.join(name), content).unwrap();
+        }
+        directory
+    }
+
+    #[test]
+    fn it_renders_named_variables() {
+        let templates = Templates::default();
+
+        assert_eq!(
+            templates.render("next_chapter.txt", &[("choice", "open the door")]),
+            "open the door\n"
+        );
+        assert_eq!(
+            substitute("{{a}} {b}}}", |_| Some(String::from("x"))),
+            Ok(String::from("{a} x}"))
+        );
+    }
+
+    #[test]
+    fn it_overrides_the_embedded_templates() {
+        let path = directory(
+            "overrides",
+            &[("next_chapter.txt", "In {language}: {choice}")],
+        );
+        let templates = Templates::load(Some(&path), "French").unwrap();
+        fs::remove_dir_all(&path).unwrap();
+
+        assert_eq!(
+            templates.render("next_chapter.txt", &[("choice", "fuir")]),
+            "In French: fuir"
+        );
+        assert_eq!(
+            templates.render("condense.txt", &[]),
+            include_str!("condense.txt")
+        );
+        assert!(templates.uses("next_chapter.txt", "choice"));
+        assert!(!templates.uses("next_chapter.txt", "state"));
+    }
+
+    #[test]
+    fn it_reports_broken_templates() {
+        let error = |files: &[(&str, &str)]| {
+            let path = directory("broken", files);
+            let result = Templates::load(Some(&path), "English");
+            fs::remove_dir_all(&path).unwrap();
+            match result {
+                Err(Error::InvalidTemplate(reason)) => reason,
+                _ => panic!("Expected an invalid template"),
+            }
+        };
+
+        assert!(error(&[("roll.txt", "{outcome} {choice}")]).contains("unknown variable {choice}"));
+        assert!(error(&[("next_chapter.txt", "Go on")]).contains("missing variable {choice}"));
+        assert!(error(&[("world_state.txt", "{state")]).starts_with("world_state.txt: unclosed"));
+        assert!(error(&[("parameters_schema.json", "{")]).starts_with("parameters_schema.json"));
+        let optional = r#"{"type": "object", "required": ["text"]}"#;
+        assert!(
+            error(&[("parameters_schema.json", optional)]).contains("\"choices\" must be required")
+        );
+    }
+}
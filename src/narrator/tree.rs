@@ -0,0 +1,231 @@
+use super::{Budget, Chapter};
+use crate::error::Result;
+use std::sync::{Arc, Mutex};
+use tokio::task::JoinHandle;
+
+pub type NodeId = usize;
+
+// Every chapter generated during the story, including the branches the reader did not follow.
+pub struct Tree {
+    nodes: Vec<Node>,
+}
+
+pub struct Node {
+    pub chapter: Chapter,
+    // Parent node and the index of the choice leading to this one.
+    pub parent: Option<(NodeId, usize)>,
+    // One per choice of the chapter, followed by one per action written by the reader.
+    pub branches: Vec<Branch>,
+    pub actions: Vec<String>,
+}
+
+pub enum Branch {
+    NotLoaded,
+    Pending(Pending),
+    Loaded(NodeId),
+}
+
+// A chapter being requested.
+pub struct Pending {
+    pub join_handle: JoinHandle<Result<Chapter>>,
+    // Shared with the request, which preloads the choices of the chapter once received if asked
+    // to.
+    pub lookahead: Arc<Mutex<Lookahead>>,
+}
+
+#[derive(Default)]
+pub struct Lookahead {
+    // A copy of the chapter, once received.
+    pub chapter: Option<Chapter>,
+    // Set while the choices are to be preloaded as soon as the chapter is received.
+    pub budget: Option<Budget>,
+    // One per choice of the chapter once its choices are preloaded.
+    pub branches: Vec<Branch>,
+}
+
+impl Tree {
+    pub fn new(root: Chapter) -> Self {
+        let mut tree = Self { nodes: Vec::new() };
+        tree.push(root, None);
+        tree
+    }
+
+    pub fn node(&self, id: NodeId) -> &Node {
+        &self.nodes[id]
+    }
+
+    pub fn get(&self, id: NodeId) -> Option<&Node> {
+        self.nodes.get(id)
+    }
+
+    pub fn branch_mut(&mut self, id: NodeId, index: usize) -> &mut Branch {
+        &mut self.nodes[id].branches[index]
+    }
+
+    // The branches of a preloaded chapter may already be loading.
+    pub fn add(
+        &mut self,
+        parent: NodeId,
+        index: usize,
+        chapter: Chapter,
+        branches: Vec<Branch>,
+    ) -> NodeId {
+        let id = self.push(chapter, Some((parent, index)));
+        if !branches.is_empty() {
+            self.nodes[id].branches = branches;
+        }
+        self.nodes[parent].branches[index] = Branch::Loaded(id);
+        id
+    }
+
+    // Preloads already received become nodes, so that they can be listed and reached like the
+    // chapters the reader followed.
+    pub fn promote_received(&mut self) {
+        let mut id = 0;
+
+        while id < self.nodes.len() {
+            for index in 0..self.nodes[id].branches.len() {
+                if let Some((chapter, branches)) = self.nodes[id].branches[index].take_received() {
+                    self.add(id, index, chapter, branches);
+                }
+            }
+            id += 1;
+        }
+    }
+
+    // Requests still running for the branches of a node are aborted and will be sent again if
+    // needed. Chapters already received are added to the tree, along with the preloads of their
+    // choices already received.
+    pub fn abort_pending(&mut self, id: NodeId) {
+        abort_running(&mut self.nodes[id].branches);
+        self.promote_received();
+    }
+
+    // Actions written by the reader are always loaded on demand, so they are added once their
+    // chapter exists.
+    pub fn add_action(&mut self, parent: NodeId, action: String, chapter: Chapter) -> NodeId {
+        let index = self.nodes[parent].branches.len();
+        let id = self.push(chapter, Some((parent, index)));
+        self.nodes[parent].branches.push(Branch::Loaded(id));
+        self.nodes[parent].actions.push(action);
+        id
+    }
+
+    // Nodes in depth-first order, along with their depth.
+    pub fn walk(&self) -> Vec<(NodeId, usize)> {
+        let mut walked = Vec::new();
+        let mut stack = vec![(0, 0)];
+
+        while let Some((id, depth)) = stack.pop() {
+            walked.push((id, depth));
+
+            for branch in self.nodes[id].branches.iter().rev() {
+                if let Branch::Loaded(child) = branch {
+                    stack.push((*child, depth + 1));
+                }
+            }
+        }
+
+        walked
+    }
+
+    fn push(&mut self, chapter: Chapter, parent: Option<(NodeId, usize)>) -> NodeId {
+        let branches = chapter
+            .choices()
+            .iter()
+            .map(|_| Branch::NotLoaded)
+            .collect();
+        self.nodes.push(Node {
+            chapter,
+            parent,
+            branches,
+            actions: Vec::new(),
+        });
+        self.nodes.len() - 1
+    }
+}
+
+fn abort_running(branches: &mut [Branch]) {
+    for branch in branches {
+        if let Branch::Pending(pending) = branch {
+            match pending.join_handle.is_finished() {
+                true => pending.stop_lookahead(),
+                false => {
+                    pending.abort();
+                    *branch = Branch::NotLoaded;
+                }
+            }
+        }
+    }
+}
+
+impl Node {
+    // The choice or action leading to the given branch.
+    pub fn label(&self, index: usize) -> &str {
+        let choices = self.chapter.choices();
+        match index.checked_sub(choices.len()) {
+            Some(action) => &self.actions[action],
+            None => &choices[index],
+        }
+    }
+}
+
+impl Branch {
+    pub fn is_ready(&self) -> bool {
+        match self {
+            Branch::NotLoaded => false,
+            Branch::Pending(pending) => pending.join_handle.is_finished(),
+            Branch::Loaded(_) => true,
+        }
+    }
+
+    // Whether the chapter of the branch exists, a failed request is not.
+    pub fn is_received(&self) -> bool {
+        match self {
+            Branch::NotLoaded => false,
+            Branch::Pending(pending) => {
+                pending.join_handle.is_finished()
+                    && pending.lookahead.lock().unwrap().chapter.is_some()
+            }
+            Branch::Loaded(_) => true,
+        }
+    }
+
+    // The chapter of a finished preload and the branches of its choices, to be added to the tree.
+    fn take_received(&self) -> Option<(Chapter, Vec<Branch>)> {
+        match self {
+            Branch::Pending(pending) if pending.join_handle.is_finished() => {
+                let chapter = pending.lookahead.lock().unwrap().chapter.take()?;
+                Some((chapter, pending.take_branches()))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Pending {
+    pub fn new(join_handle: JoinHandle<Result<Chapter>>, lookahead: Arc<Mutex<Lookahead>>) -> Self {
+        Self {
+            join_handle,
+            lookahead,
+        }
+    }
+
+    pub fn abort(&self) {
+        self.stop_lookahead();
+        self.join_handle.abort();
+    }
+
+    // The request no longer preloads anything once its chapter is received, and the preloads of
+    // its choices still running are aborted.
+    fn stop_lookahead(&self) {
+        let mut lookahead = self.lookahead.lock().unwrap();
+        lookahead.budget = None;
+        abort_running(&mut lookahead.branches);
+    }
+
+    // The branches of the chapter, to be added to the tree along with it.
+    pub fn take_branches(&self) -> Vec<Branch> {
+        std::mem::take(&mut self.lookahead.lock().unwrap().branches)
+    }
+}
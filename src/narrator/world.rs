@@ -0,0 +1,122 @@
+use super::templates;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+// What the story has established so far, kept outside of the prose so that it does not drift.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct WorldState {
+    pub location: Option<String>,
+    pub inventory: Vec<String>,
+    // Such as health or gold.
+    pub stats: BTreeMap<String, i64>,
+    // Plot flags, of any JSON value.
+    pub flags: BTreeMap<String, Value>,
+}
+
+// Changes made by a chapter, as returned along with it.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct StateDelta {
+    pub location: Option<String>,
+    pub items_gained: Vec<String>,
+    pub items_lost: Vec<String>,
+    // Added to the current values, missing stats start at 0.
+    pub stats: BTreeMap<String, i64>,
+    // Null clears a flag.
+    pub flags: BTreeMap<String, Value>,
+}
+
+impl WorldState {
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    pub fn apply(&mut self, delta: &StateDelta) {
+        if let Some(location) = &delta.location {
+            self.location = Some(location.clone());
+        }
+
+        self.inventory.extend(delta.items_gained.iter().cloned());
+        for item in &delta.items_lost {
+            if let Some(index) = self.inventory.iter().position(|owned| owned == item) {
+                self.inventory.remove(index);
+            }
+        }
+
+        for (name, change) in &delta.stats {
+            let stat = self.stats.entry(name.clone()).or_default();
+            *stat = stat.saturating_add(*change);
+        }
+
+        for (name, value) in &delta.flags {
+            match value {
+                Value::Null => self.flags.remove(name),
+                value => self.flags.insert(name.clone(), value.clone()),
+            };
+        }
+    }
+
+    // Given to the model along with each choice, so that chapters stay consistent with it.
+    pub fn prompt(&self, player: &str) -> Option<String> {
+        match self.is_empty() {
+            true => None,
+            false => Some(templates().render(
+                "world_state.txt",
+                &[
+                    ("state", &serde_json::to_string(self).unwrap()),
+                    ("player", player),
+                ],
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn it_applies_deltas() {
+        let mut state = WorldState::default();
+        let delta = |value| serde_json::from_value::<StateDelta>(value).unwrap();
+
+        state.apply(&delta(json!({
+            "location": "the cave",
+            "items_gained": ["torch", "rope", "torch"],
+            "stats": { "health": 10 },
+            "flags": { "met_the_witch": true },
+        })));
+        state.apply(&delta(json!({
+            "items_lost": ["torch", "sword"],
+            "stats": { "health": -3, "gold": 5 },
+            "flags": { "met_the_witch": null, "door": "open" },
+        })));
+
+        assert_eq!(state.location.as_deref(), Some("the cave"));
+        assert_eq!(state.inventory, vec!["rope", "torch"]);
+        assert_eq!(state.stats["health"], 7);
+        assert_eq!(state.stats["gold"], 5);
+        assert_eq!(
+            state.flags,
+            BTreeMap::from([(String::from("door"), json!("open"))])
+        );
+
+        state.apply(&delta(json!({ "stats": { "gold": i64::MAX } })));
+        assert_eq!(state.stats["gold"], i64::MAX);
+    }
+
+    #[test]
+    fn it_only_prompts_for_a_known_state() {
+        let mut state = WorldState::default();
+        assert_eq!(state.prompt("Sam"), None);
+
+        state.inventory.push(String::from("rope"));
+        assert!(state
+            .prompt("Sam")
+            .unwrap()
+            .contains("\"inventory\":[\"rope\"]"));
+    }
+}